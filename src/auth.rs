@@ -0,0 +1,374 @@
+//! Authentication and authorization.
+//!
+//! Two kinds of clients are told apart by the bearer credential:
+//!
+//! - Services send an API key, `Authorization: Bearer mk_...` (or
+//!   `X-Api-Key`). Keys are random, only their SHA-256 is stored, and each
+//!   carries scopes: read or write per resource, or `admin`. The scope a
+//!   request needs follows from its path and method, see [`required_access`].
+//!   Keys are managed through `/api-keys` (admin scope) or `maratus api-keys`.
+//! - End-user clients send a JWT whose subject is a participant id, see
+//!   [`crate::jwt`]. Participants reach only their own profile, inbox and
+//!   event stream, the conversations they are an active member of, and can
+//!   only send messages as themselves. Membership is checked by the
+//!   handlers through [`ensure_member`] and [`authorize_conversation`].
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    middleware::Next,
+    web,
+    HttpMessage,
+};
+use bson::{doc, DateTime as BsonDateTime};
+use futures::TryStreamExt;
+use mongodb::{options::ReturnDocument, Database};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::error::{AppError, AppResult};
+use crate::ids::{ApiKeyId, ConversationId, ParticipantId};
+use crate::jwt::JwtVerifier;
+use crate::models::{ApiKey, Conversation, Participant, ParticipantRedirect, Resource, Scope};
+
+const KEY_PREFIX: &str = "mk_";
+/// Characters of the key kept in clear for listings.
+const SHOWN_PREFIX_LEN: usize = 11;
+const API_KEY_HEADER: &str = "X-Api-Key";
+/// `last_used_at` is refreshed at most this often per key.
+const LAST_USED_RESOLUTION_MS: i64 = 60_000;
+
+/// Who made a request, available to handlers as `ReqData<Principal>` when
+/// authentication is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Principal {
+    ApiKey(ApiKeyId),
+    Participant(ParticipantId),
+}
+
+impl Principal {
+    pub fn participant(self) -> Option<ParticipantId> {
+        match self {
+            Principal::Participant(id) => Some(id),
+            Principal::ApiKey(_) => None,
+        }
+    }
+}
+
+/// What a request needs to be let through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Public,
+    /// Any valid key, for paths that match no resource.
+    Authenticated,
+    Scoped(Scope),
+}
+
+/// Maps a request to the scope it needs. Reads are `GET` and `HEAD`, every
+/// other method writes. Event streams nested under participants and
+/// conversations count as events.
+pub fn required_access(method: &Method, path: &str) -> Access {
+    let mut segments = path.trim_matches('/').split('/');
+    let first = segments.next().unwrap_or_default();
+    let nested_events = segments.nth(1) == Some("events");
+    let read = matches!(*method, Method::GET | Method::HEAD);
+    let scoped = |resource| Access::Scoped(if read { Scope::Read(resource) } else { Scope::Write(resource) });
+
+    match first {
+        "health" => Access::Public,
+        "api-keys" => Access::Scoped(Scope::Admin),
+        _ if nested_events => scoped(Resource::Events),
+        "participants" => scoped(Resource::Participants),
+        "conversations" => scoped(Resource::Conversations),
+        "messages" | "message-summaries" => scoped(Resource::Messages),
+        "agent-jobs" => scoped(Resource::AgentJobs),
+        "events" => scoped(Resource::Events),
+        "webhooks" | "webhook-deliveries" => scoped(Resource::Webhooks),
+        // The gateway sends messages on the client's behalf.
+        "ws" => Access::Scoped(Scope::Write(Resource::Messages)),
+        _ => Access::Authenticated,
+    }
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn generate_key() -> String {
+    format!("{KEY_PREFIX}{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Stores a new key and returns it with the secret, which is not shown again.
+pub async fn create_key(db: &Database, name: String, scopes: Vec<Scope>) -> mongodb::error::Result<(ApiKey, String)> {
+    let secret = generate_key();
+    let key = ApiKey {
+        id: ApiKeyId::generate(),
+        name,
+        prefix: secret[..SHOWN_PREFIX_LEN].to_string(),
+        key_hash: hash_key(&secret),
+        scopes,
+        created_at: BsonDateTime::now(),
+        last_used_at: None,
+        revoked_at: None,
+    };
+    db.collection::<ApiKey>("api_keys")
+        .insert_one(&key)
+        .await?;
+    Ok((key, secret))
+}
+
+pub async fn list_keys(db: &Database) -> mongodb::error::Result<Vec<ApiKey>> {
+    db.collection::<ApiKey>("api_keys")
+        .find(doc! {})
+        .sort(doc! { "created_at": 1 })
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Revoked keys are kept so listings show what existed. Revoking twice keeps
+/// the first revocation time.
+pub async fn revoke_key(db: &Database, id: ApiKeyId) -> mongodb::error::Result<Option<ApiKey>> {
+    let key_coll = db.collection::<ApiKey>("api_keys");
+    let revoked = key_coll
+        .find_one_and_update(
+            doc! { "_id": id, "revoked_at": null },
+            doc! { "$set": { "revoked_at": BsonDateTime::now() } },
+        )
+        .return_document(ReturnDocument::After)
+        .await?;
+    match revoked {
+        Some(key) => Ok(Some(key)),
+        None => key_coll.find_one(doc! { "_id": id }).await,
+    }
+}
+
+/// Routes open to participant tokens. Paths naming a participant must name
+/// the caller; conversation and message access is checked by the handlers.
+pub fn participant_may_access(method: &Method, path: &str, caller: ParticipantId) -> bool {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let is_caller = |id: &str| id.parse::<ParticipantId>().is_ok_and(|id| id == caller);
+    match (method.clone(), segments.as_slice()) {
+        (Method::GET, ["conversations", _] | ["conversations", _, "summaries" | "events"]) => true,
+        (Method::GET, ["messages", _] | ["ws"]) | (Method::POST, ["messages"]) => true,
+        (Method::GET, ["participants", id] | ["participants", id, "conversations" | "events"]) => is_caller(id),
+        (Method::PUT, ["participants", id, "conversations", _, "read"]) => is_caller(id),
+        _ => false,
+    }
+}
+
+/// Participants only see conversations they are an active member of, API
+/// keys and unauthenticated deployments see all.
+pub fn ensure_member(principal: Option<Principal>, conv: &Conversation) -> AppResult<()> {
+    let Some(caller) = principal.and_then(Principal::participant) else { return Ok(()) };
+    if conv.membership(&caller).is_some_and(|cp| cp.left_at.is_none()) {
+        Ok(())
+    } else {
+        Err(AppError::forbidden("not_a_member", "Not a member of this conversation"))
+    }
+}
+
+/// [`ensure_member`] for handlers that have not loaded the conversation.
+pub async fn authorize_conversation(db: &Database, principal: Option<Principal>, conv_id: ConversationId) -> AppResult<()> {
+    if principal.and_then(Principal::participant).is_none() {
+        return Ok(());
+    }
+    let conv = db.collection::<Conversation>("conversations")
+        .find_one(doc! { "_id": conv_id })
+        .await?
+        .ok_or_else(|| AppError::not_found("conversation_not_found", "Conversation not found"))?;
+    ensure_member(principal, &conv)
+}
+
+enum Credential {
+    ApiKey(String),
+    Token(String),
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    access_token: Option<String>,
+}
+
+/// Browsers cannot set headers on WebSocket requests, so `/ws` also takes
+/// the token as `?access_token=`.
+fn presented_credential(req: &ServiceRequest) -> Option<Credential> {
+    let headers = req.headers();
+    if let Some(value) = headers.get(API_KEY_HEADER) {
+        return value.to_str().ok().map(|key| Credential::ApiKey(key.to_string()));
+    }
+    let bearer = headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| value.trim().to_string())
+        .or_else(|| {
+            let query = web::Query::<TokenQuery>::from_query(req.query_string()).ok()?;
+            query.into_inner().access_token.filter(|_| req.path() == "/ws")
+        })?;
+    if bearer.starts_with(KEY_PREFIX) {
+        Some(Credential::ApiKey(bearer))
+    } else {
+        Some(Credential::Token(bearer))
+    }
+}
+
+async fn authenticate_key(db: &Database, presented: &str) -> Result<ApiKey, AppError> {
+    let key = db.collection::<ApiKey>("api_keys")
+        .find_one(doc! { "key_hash": hash_key(presented), "revoked_at": null })
+        .await?
+        .ok_or_else(|| AppError::unauthorized("invalid_api_key", "The API key is unknown or revoked"))?;
+
+    let now = BsonDateTime::now();
+    let stale = BsonDateTime::from_millis(now.timestamp_millis() - LAST_USED_RESOLUTION_MS);
+    if key.last_used_at.is_none_or(|used| used < stale) {
+        let db = db.clone();
+        let id = key.id;
+        actix_web::rt::spawn(async move {
+            let touched = db.collection::<ApiKey>("api_keys")
+                .update_one(doc! { "_id": id }, doc! { "$set": { "last_used_at": now } })
+                .await;
+            if let Err(e) = touched {
+                eprintln!("Failed to record use of API key {id}: {e}");
+            }
+        });
+    }
+    Ok(key)
+}
+
+/// Resolves the token subject, following the redirect left behind when the
+/// participant was merged into another one.
+async fn authenticate_token(db: &Database, verifier: Option<&JwtVerifier>, token: &str) -> Result<ParticipantId, AppError> {
+    let verifier = verifier
+        .ok_or_else(|| AppError::unauthorized("invalid_token", "This server does not accept participant tokens"))?;
+    let subject = verifier.verify(token)?;
+
+    let exists = db.collection::<Participant>("participants")
+        .find_one(doc! { "_id": subject })
+        .await?
+        .is_some();
+    if exists {
+        return Ok(subject);
+    }
+    db.collection::<ParticipantRedirect>("participant_redirects")
+        .find_one(doc! { "_id": subject })
+        .await?
+        .map(|redirect| redirect.target_id)
+        .ok_or_else(|| AppError::unauthorized("unknown_subject", "The token subject is not a participant"))
+}
+
+/// Middleware rejecting requests without credentials for the route: an API
+/// key granting the scope it needs, or a participant token for a route open
+/// to participants.
+pub async fn require_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let needed = match required_access(req.method(), req.path()) {
+        Access::Public => return next.call(req).await,
+        Access::Authenticated => None,
+        Access::Scoped(scope) => Some(scope),
+    };
+
+    let db = req.app_data::<web::Data<Database>>()
+        .ok_or_else(|| AppError::Internal("database missing from app data".to_string()))?
+        .get_ref()
+        .clone();
+    let verifier = req.app_data::<web::Data<Option<JwtVerifier>>>()
+        .and_then(|verifier| verifier.get_ref().as_ref());
+
+    let principal = match presented_credential(&req) {
+        None => return Err(AppError::unauthorized("missing_credentials", "An API key or token is required").into()),
+        Some(Credential::ApiKey(presented)) => {
+            let key = authenticate_key(&db, &presented).await?;
+            if let Some(needed) = needed {
+                if !key.scopes.iter().any(|scope| scope.grants(needed)) {
+                    return Err(AppError::forbidden(
+                        "insufficient_scope",
+                        format!("The API key lacks the {needed} scope"),
+                    ).into());
+                }
+            }
+            Principal::ApiKey(key.id)
+        }
+        Some(Credential::Token(token)) => {
+            let caller = authenticate_token(&db, verifier, &token).await?;
+            if !participant_may_access(req.method(), req.path(), caller) {
+                return Err(AppError::forbidden(
+                    "participant_forbidden",
+                    "Participant tokens cannot access this endpoint",
+                ).into());
+            }
+            Principal::Participant(caller)
+        }
+    };
+
+    req.extensions_mut().insert(principal);
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_map_to_the_scope_they_need() {
+        use Resource::*;
+        let cases = [
+            (Method::GET, "/health/ready", Access::Public),
+            (Method::GET, "/api-keys", Access::Scoped(Scope::Admin)),
+            (Method::DELETE, "/api-keys/x", Access::Scoped(Scope::Admin)),
+            (Method::GET, "/participants", Access::Scoped(Scope::Read(Participants))),
+            (Method::HEAD, "/participants/x", Access::Scoped(Scope::Read(Participants))),
+            (Method::PATCH, "/participants/x", Access::Scoped(Scope::Write(Participants))),
+            (Method::PUT, "/participants/x/ai-config", Access::Scoped(Scope::Write(Participants))),
+            (Method::GET, "/conversations/x", Access::Scoped(Scope::Read(Conversations))),
+            (Method::POST, "/conversations", Access::Scoped(Scope::Write(Conversations))),
+            (Method::POST, "/messages/batch", Access::Scoped(Scope::Write(Messages))),
+            (Method::GET, "/message-summaries", Access::Scoped(Scope::Read(Messages))),
+            (Method::POST, "/agent-jobs/claim", Access::Scoped(Scope::Write(AgentJobs))),
+            (Method::GET, "/events", Access::Scoped(Scope::Read(Events))),
+            (Method::GET, "/conversations/x/events", Access::Scoped(Scope::Read(Events))),
+            (Method::GET, "/participants/x/events", Access::Scoped(Scope::Read(Events))),
+            (Method::DELETE, "/webhooks/x", Access::Scoped(Scope::Write(Webhooks))),
+            (Method::GET, "/webhook-deliveries", Access::Scoped(Scope::Read(Webhooks))),
+            (Method::GET, "/ws", Access::Scoped(Scope::Write(Messages))),
+            (Method::GET, "/nope", Access::Authenticated),
+        ];
+        for (method, path, access) in cases {
+            assert_eq!(required_access(&method, path), access, "{method} {path}");
+        }
+    }
+
+    #[test]
+    fn participants_reach_only_their_own_routes() {
+        let caller = ParticipantId::generate();
+        let other = ParticipantId::generate();
+        let cases = [
+            (Method::GET, "/conversations/abc".to_string(), true),
+            (Method::GET, "/conversations/abc/summaries".to_string(), true),
+            (Method::GET, "/conversations/abc/events".to_string(), true),
+            (Method::GET, "/conversations".to_string(), false),
+            (Method::PUT, "/conversations/abc/metadata".to_string(), false),
+            (Method::GET, "/messages/abc".to_string(), true),
+            (Method::POST, "/messages".to_string(), true),
+            (Method::POST, "/messages/batch".to_string(), false),
+            (Method::GET, "/ws".to_string(), true),
+            (Method::GET, format!("/participants/{caller}"), true),
+            (Method::GET, format!("/participants/{caller}/conversations"), true),
+            (Method::GET, format!("/participants/{caller}/events"), true),
+            (Method::PUT, format!("/participants/{caller}/conversations/abc/read"), true),
+            (Method::PATCH, format!("/participants/{caller}"), false),
+            (Method::GET, format!("/participants/{other}"), false),
+            (Method::GET, format!("/participants/{other}/events"), false),
+            (Method::PUT, format!("/participants/{other}/conversations/abc/read"), false),
+            (Method::GET, "/participants/not-an-id".to_string(), false),
+            (Method::GET, "/events".to_string(), false),
+            (Method::GET, "/api-keys".to_string(), false),
+        ];
+        for (method, path, allowed) in cases {
+            assert_eq!(participant_may_access(&method, &path, caller), allowed, "{method} {path}");
+        }
+    }
+}
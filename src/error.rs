@@ -0,0 +1,264 @@
+//! Errors returned by the API.
+//!
+//! Every failure is rendered as an RFC 7807 `application/problem+json` body.
+//! Besides the standard members it carries a `code` that stays the same
+//! across releases, so clients can match on it instead of on the wording of
+//! `detail`. Database and other internal errors are logged and answered with
+//! a generic message, driver internals never reach the client.
+//!
+//! A body that is not JSON at all, is too large or has the wrong content type
+//! is rejected with 400 `invalid_body`. Well-formed JSON that does not fit
+//! the payload, e.g. a missing field or a string where a number belongs, and
+//! values failing validation are answered with 422 `validation_failed`,
+//! listing the rejected fields in `errors`; mistyped bodies are reported
+//! under the field `body`, as the deserializer does not say which field it
+//! was reading.
+
+use std::fmt;
+
+use actix_web::{
+    error::{JsonPayloadError, PathError, QueryPayloadError},
+    http::{header, StatusCode},
+    HttpRequest,
+    HttpResponse,
+    ResponseError,
+};
+use serde::Serialize;
+use serde_json::error::Category;
+
+const PROBLEM_JSON: &str = "application/problem+json";
+const INTERNAL_DETAIL: &str = "An internal error occurred";
+
+pub type AppResult<T> = Result<T, AppError>;
+
+/// A rejected field of the request body.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest { code: &'static str, detail: String },
+    /// Missing or invalid credentials.
+    Unauthorized { code: &'static str, detail: String },
+    /// One or more fields of a well-formed request are invalid, answered
+    /// with 422 and the rejected fields in `errors`.
+    Validation(Vec<FieldError>),
+    Forbidden { code: &'static str, detail: String },
+    NotFound { code: &'static str, detail: String },
+    Conflict { code: &'static str, detail: String },
+    Gone { code: &'static str, detail: String },
+    /// The server cannot handle requests yet, e.g. during startup.
+    Unavailable { code: &'static str, detail: String },
+    Database(mongodb::error::Error),
+    Internal(String),
+}
+
+impl AppError {
+    pub fn bad_request(code: &'static str, detail: impl Into<String>) -> Self {
+        AppError::BadRequest { code, detail: detail.into() }
+    }
+
+    pub fn unauthorized(code: &'static str, detail: impl Into<String>) -> Self {
+        AppError::Unauthorized { code, detail: detail.into() }
+    }
+
+    pub fn forbidden(code: &'static str, detail: impl Into<String>) -> Self {
+        AppError::Forbidden { code, detail: detail.into() }
+    }
+
+    pub fn not_found(code: &'static str, detail: impl Into<String>) -> Self {
+        AppError::NotFound { code, detail: detail.into() }
+    }
+
+    pub fn conflict(code: &'static str, detail: impl Into<String>) -> Self {
+        AppError::Conflict { code, detail: detail.into() }
+    }
+
+    pub fn gone(code: &'static str, detail: impl Into<String>) -> Self {
+        AppError::Gone { code, detail: detail.into() }
+    }
+
+    pub fn unavailable(code: &'static str, detail: impl Into<String>) -> Self {
+        AppError::Unavailable { code, detail: detail.into() }
+    }
+
+    /// A single invalid field.
+    pub fn invalid(field: &str, code: &str, message: impl Into<String>) -> Self {
+        AppError::Validation(vec![FieldError {
+            field: field.to_string(),
+            code: code.to_string(),
+            message: message.into(),
+        }])
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest { code, .. }
+            | AppError::Unauthorized { code, .. }
+            | AppError::Forbidden { code, .. }
+            | AppError::NotFound { code, .. }
+            | AppError::Conflict { code, .. }
+            | AppError::Gone { code, .. }
+            | AppError::Unavailable { code, .. } => code,
+            AppError::Validation(_) => "validation_failed",
+            AppError::Database(_) | AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// The response body. Internal errors are logged here, so call it once
+    /// per error that is reported to a client.
+    pub fn problem(&self) -> Problem {
+        let status = self.status_code();
+        let (detail, errors) = match self {
+            AppError::BadRequest { detail, .. }
+            | AppError::Unauthorized { detail, .. }
+            | AppError::Forbidden { detail, .. }
+            | AppError::NotFound { detail, .. }
+            | AppError::Conflict { detail, .. }
+            | AppError::Gone { detail, .. }
+            | AppError::Unavailable { detail, .. } => (detail.clone(), Vec::new()),
+            AppError::Validation(errors) => ("The request has invalid fields".to_string(), errors.clone()),
+            AppError::Database(e) => {
+                eprintln!("Database error: {e}");
+                (INTERNAL_DETAIL.to_string(), Vec::new())
+            }
+            AppError::Internal(e) => {
+                eprintln!("Internal error: {e}");
+                (INTERNAL_DETAIL.to_string(), Vec::new())
+            }
+        };
+        Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            code: self.code(),
+            detail,
+            errors,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::BadRequest { detail, .. }
+            | AppError::Unauthorized { detail, .. }
+            | AppError::Forbidden { detail, .. }
+            | AppError::NotFound { detail, .. }
+            | AppError::Conflict { detail, .. }
+            | AppError::Gone { detail, .. }
+            | AppError::Unavailable { detail, .. } => f.write_str(detail),
+            AppError::Validation(errors) => {
+                let fields: Vec<String> = errors.iter().map(|e| format!("{}: {}", e.field, e.message)).collect();
+                f.write_str(&fields.join("; "))
+            }
+            AppError::Database(e) => write!(f, "database error: {e}"),
+            AppError::Internal(e) => f.write_str(e),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            AppError::NotFound { .. } => StatusCode::NOT_FOUND,
+            AppError::Conflict { .. } => StatusCode::CONFLICT,
+            AppError::Gone { .. } => StatusCode::GONE,
+            AppError::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        res.insert_header((header::CONTENT_TYPE, PROBLEM_JSON));
+        if let AppError::Unauthorized { .. } = self {
+            res.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        res.json(self.problem())
+    }
+}
+
+impl From<mongodb::error::Error> for AppError {
+    fn from(e: mongodb::error::Error) -> Self {
+        AppError::Database(e)
+    }
+}
+
+impl From<bson::ser::Error> for AppError {
+    fn from(e: bson::ser::Error) -> Self {
+        AppError::Internal(format!("failed to serialize document: {e}"))
+    }
+}
+
+impl From<bson::de::Error> for AppError {
+    fn from(e: bson::de::Error) -> Self {
+        AppError::Internal(format!("failed to deserialize document: {e}"))
+    }
+}
+
+/// RFC 7807 body, also embedded in batch results and websocket frames.
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub code: &'static str,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+// Extractor rejections, registered on the app so they render like every
+// other error.
+
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::Deserialize(e) if e.classify() == Category::Data => {
+            AppError::invalid("body", "invalid_type", e.to_string()).into()
+        }
+        err => AppError::bad_request("invalid_body", err.to_string()).into(),
+    }
+}
+
+pub fn query_error(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    AppError::bad_request("invalid_query", err.to_string()).into()
+}
+
+/// Malformed ids in the path are a client error, not a missing resource.
+pub fn path_error(err: PathError, _req: &HttpRequest) -> actix_web::Error {
+    AppError::bad_request("invalid_path", err.to_string()).into()
+}
+
+pub async fn route_not_found() -> AppResult<HttpResponse> {
+    Err(AppError::not_found("route_not_found", "No such endpoint"))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn status(body: &str) -> StatusCode {
+        let err = serde_json::from_str::<Vec<u32>>(body).unwrap_err();
+        let req = TestRequest::default().to_http_request();
+        json_error(JsonPayloadError::Deserialize(err), &req).as_response_error().status_code()
+    }
+
+    #[test]
+    fn mistyped_bodies_are_invalid_fields_and_broken_ones_bad_requests() {
+        assert_eq!(status(r#"["one"]"#), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(status("[1,"), StatusCode::BAD_REQUEST);
+        assert_eq!(status("[1 2]"), StatusCode::BAD_REQUEST);
+    }
+}
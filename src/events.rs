@@ -0,0 +1,373 @@
+use std::sync::Arc;
+
+use bson::{doc, Bson, DateTime as BsonDateTime, Document};
+use futures::TryStreamExt;
+use mongodb::{options::ReturnDocument, ClientSession, Database};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::ids::{ConversationId, ParticipantId};
+use crate::models::Conversation;
+
+/// Capacity of the live channel; slower subscribers catch up from the log.
+const LIVE_CAPACITY: usize = 1024;
+const EVENT_SEQUENCE: &str = "events";
+/// How long a missing sequence number is waited for. Only writes without a
+/// transaction leave gaps, and those finish well within this.
+const SETTLE_MS: i64 = 30_000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    #[serde(rename = "participant.created")]
+    ParticipantCreated,
+    #[serde(rename = "participant.updated")]
+    ParticipantUpdated,
+    #[serde(rename = "participant.merged")]
+    ParticipantMerged,
+    #[serde(rename = "conversation.created")]
+    ConversationCreated,
+    #[serde(rename = "conversation.updated")]
+    ConversationUpdated,
+    #[serde(rename = "conversation.read")]
+    ConversationRead,
+    #[serde(rename = "message.created")]
+    MessageCreated,
+    #[serde(rename = "message.updated")]
+    MessageUpdated,
+    #[serde(rename = "summary.created")]
+    SummaryCreated,
+    #[serde(rename = "agent_job.created")]
+    AgentJobCreated,
+    #[serde(rename = "agent_job.updated")]
+    AgentJobUpdated,
+}
+
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::ParticipantCreated => "participant.created",
+            EventKind::ParticipantUpdated => "participant.updated",
+            EventKind::ParticipantMerged => "participant.merged",
+            EventKind::ConversationCreated => "conversation.created",
+            EventKind::ConversationUpdated => "conversation.updated",
+            EventKind::ConversationRead => "conversation.read",
+            EventKind::MessageCreated => "message.created",
+            EventKind::MessageUpdated => "message.updated",
+            EventKind::SummaryCreated => "summary.created",
+            EventKind::AgentJobCreated => "agent_job.created",
+            EventKind::AgentJobUpdated => "agent_job.updated",
+        }
+    }
+}
+
+// ___ events collection (append-only change log) ___
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Event {
+    /// Monotonically increasing sequence number.
+    #[serde(rename = "_id")]
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub conversation_id: Option<ConversationId>,
+    /// Participants the event is relevant to, used by per-participant streams.
+    pub participant_ids: Vec<ParticipantId>,
+    pub data: Bson,
+    pub created_at: BsonDateTime,
+}
+
+impl Event {
+    /// Whether the event updates a conversation that `participant_id` is no
+    /// longer an active member of, e.g. because they were just removed.
+    pub fn ends_membership(&self, participant_id: ParticipantId) -> bool {
+        self.kind == EventKind::ConversationUpdated
+            && bson::from_bson::<Conversation>(self.data.clone())
+                .is_ok_and(|conv| conv.membership(&participant_id).is_none_or(|cp| cp.left_at.is_some()))
+    }
+}
+
+/// Typing indicators are fire-and-forget: they get no sequence number and are
+/// not written to the log.
+#[derive(Debug, Serialize, Clone)]
+pub struct TypingSignal {
+    pub conversation_id: ConversationId,
+    pub participant_id: ParticipantId,
+    pub typing: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Counter {
+    seq: u64,
+}
+
+/// Where an event sits in the log, read to find gaps.
+#[derive(Debug, Deserialize)]
+struct LogPosition {
+    #[serde(rename = "_id")]
+    id: u64,
+    created_at: BsonDateTime,
+}
+
+/// A page of the log for consumers following it by sequence number.
+#[derive(Debug, Serialize)]
+pub struct EventPage {
+    pub events: Vec<Event>,
+    /// Pass back as `since` to continue. Also moves past events the filter
+    /// skipped; equals the request's `since` when there was nothing new.
+    pub next_since: u64,
+}
+
+struct Inner {
+    db: Database,
+    sender: broadcast::Sender<Event>,
+    typing: broadcast::Sender<TypingSignal>,
+}
+
+/// Appends every change to the `events` log and fans it out to live
+/// subscribers. The log is the source of truth: live subscribers that fall
+/// behind, and consumers of `GET /events`, replay from it by sequence number.
+///
+/// Writers record events in the transaction of the change they describe, see
+/// [`EventBus::record`], so a change and its event are stored together or
+/// not at all. Taking the sequence number inside the transaction also orders
+/// the commits across instances. Without transactions an event is written
+/// right after its change, and a later number can become visible before an
+/// earlier one; [`EventBus::since`] therefore stops in front of gaps.
+#[derive(Clone)]
+pub struct EventBus {
+    inner: Arc<Inner>,
+}
+
+impl EventBus {
+    pub fn new(db: Database) -> Self {
+        let (sender, _) = broadcast::channel(LIVE_CAPACITY);
+        let (typing, _) = broadcast::channel(LIVE_CAPACITY);
+        EventBus {
+            inner: Arc::new(Inner { db, sender, typing }),
+        }
+    }
+
+    /// Records an event about a conversation for its active members plus any
+    /// `extra` participants (e.g. someone who was just removed).
+    pub async fn record_for(
+        &self,
+        session: &mut ClientSession,
+        kind: EventKind,
+        conv: &Conversation,
+        extra: &[ParticipantId],
+        data: &impl Serialize,
+    ) -> mongodb::error::Result<Event> {
+        let mut participant_ids: Vec<ParticipantId> = conv.participants.iter()
+            .filter(|cp| cp.left_at.is_none())
+            .map(|cp| cp.participant_id)
+            .collect();
+        for id in extra {
+            if !participant_ids.contains(id) {
+                participant_ids.push(*id);
+            }
+        }
+        self.record(session, kind, Some(conv.id), participant_ids, data).await
+    }
+
+    /// Records an event about participants that is not tied to a conversation.
+    pub async fn record_for_participants(
+        &self,
+        session: &mut ClientSession,
+        kind: EventKind,
+        participant_ids: &[ParticipantId],
+        data: &impl Serialize,
+    ) -> mongodb::error::Result<Event> {
+        self.record(session, kind, None, participant_ids.to_vec(), data).await
+    }
+
+    /// Appends an event to the log on the caller's session. Live subscribers
+    /// only hear of it through [`EventBus::announce`], once the caller has
+    /// committed.
+    pub async fn record(
+        &self,
+        session: &mut ClientSession,
+        kind: EventKind,
+        conversation_id: Option<ConversationId>,
+        participant_ids: Vec<ParticipantId>,
+        data: &impl Serialize,
+    ) -> mongodb::error::Result<Event> {
+        let data = bson::to_bson(data)?;
+
+        let counter = self.inner.db.collection::<Counter>("counters")
+            .find_one_and_update(
+                doc! { "_id": EVENT_SEQUENCE },
+                doc! { "$inc": { "seq": 1_i64 } },
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| mongodb::error::Error::custom("event sequence counter missing after upsert"))?;
+
+        let event = Event {
+            id: counter.seq,
+            kind,
+            conversation_id,
+            participant_ids,
+            data,
+            created_at: BsonDateTime::now(),
+        };
+
+        self.inner.db.collection::<Event>("events")
+            .insert_one(&event)
+            .session(&mut *session)
+            .await?;
+
+        Ok(event)
+    }
+
+    /// Hands a committed event to live subscribers. Events of concurrent
+    /// requests can arrive out of order, subscribers go back to the log when
+    /// they see a gap.
+    pub fn announce(&self, event: Event) {
+        // Sending only fails when nobody is listening.
+        let _ = self.inner.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.inner.sender.subscribe()
+    }
+
+    pub fn publish_typing(&self, signal: TypingSignal) {
+        let _ = self.inner.typing.send(signal);
+    }
+
+    pub fn subscribe_typing(&self) -> broadcast::Receiver<TypingSignal> {
+        self.inner.typing.subscribe()
+    }
+
+    /// Sequence number up to which the whole log can be read, the starting
+    /// point for consumers that are not interested in the past.
+    pub async fn head(&self) -> mongodb::error::Result<u64> {
+        self.horizon(0).await
+    }
+
+    /// Logged events newer than `last_id` that match `filter`, oldest first.
+    /// The page ends in front of any event that may still be written.
+    pub async fn since(
+        &self,
+        last_id: u64,
+        mut filter: Document,
+        limit: i64,
+    ) -> mongodb::error::Result<EventPage> {
+        let horizon = self.horizon(last_id).await?;
+        if horizon <= last_id {
+            return Ok(EventPage { events: Vec::new(), next_since: last_id });
+        }
+
+        filter.insert("_id", doc! { "$gt": last_id as i64, "$lte": horizon as i64 });
+        let events: Vec<Event> = self.inner.db.collection::<Event>("events")
+            .find(filter)
+            .sort(doc! { "_id": 1 })
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await?;
+
+        // A short page holds everything up to the horizon.
+        let next_since = match events.last() {
+            Some(last) if events.len() as i64 >= limit => last.id,
+            _ => horizon,
+        };
+        Ok(EventPage { events, next_since })
+    }
+
+    /// Highest sequence number that can be read after `last_id` without
+    /// skipping an event that is still being written. A number missing in
+    /// front of events younger than [`SETTLE_MS`] may still show up; one
+    /// followed by older events belongs to a write that failed and is
+    /// skipped. Only the recent end of the log is read.
+    async fn horizon(&self, last_id: u64) -> mongodb::error::Result<u64> {
+        let cutoff = BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() - SETTLE_MS);
+        let mut cursor = self.inner.db.collection::<LogPosition>("events")
+            .find(doc! { "_id": { "$gt": last_id as i64 } })
+            .sort(doc! { "_id": -1 })
+            .projection(doc! { "created_at": 1 })
+            .await?;
+
+        let mut newest = None;
+        let mut settled = None;
+        let mut recent = Vec::new();
+        while let Some(position) = cursor.try_next().await? {
+            newest.get_or_insert(position.id);
+            if position.created_at < cutoff {
+                settled = Some(position.id);
+                break;
+            }
+            recent.push(position.id);
+        }
+
+        let Some(newest) = newest else { return Ok(last_id) };
+        let Some(&first) = recent.last() else { return Ok(newest) };
+        // Anything between the settled end and the recent events may still
+        // be written.
+        if first - 1 > last_id && settled != Some(first - 1) {
+            return Ok(settled.unwrap_or(last_id));
+        }
+        let mut horizon = first;
+        for &id in recent.iter().rev().skip(1) {
+            if id != horizon + 1 {
+                break;
+            }
+            horizon = id;
+        }
+        Ok(horizon)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ConvParticipant, ConvRole};
+
+    fn updated(member: ParticipantId, left: bool) -> Event {
+        let conv = Conversation {
+            id: ConversationId::generate(),
+            external_id: "conv".to_string(),
+            topic: None,
+            started_at: BsonDateTime::now(),
+            last_message_at: None,
+            last_message_id: None,
+            message_count: 0,
+            channel_counts: Default::default(),
+            participants: vec![ConvParticipant {
+                participant_id: member,
+                role: ConvRole::Member,
+                joined_at: BsonDateTime::now(),
+                left_at: left.then(BsonDateTime::now),
+                last_read_at: None,
+            }],
+            allow_departed_senders: false,
+            summary: None,
+            context: None,
+        };
+        Event {
+            id: 1,
+            kind: EventKind::ConversationUpdated,
+            conversation_id: Some(conv.id),
+            participant_ids: vec![member],
+            data: bson::to_bson(&conv).unwrap(),
+            created_at: BsonDateTime::now(),
+        }
+    }
+
+    #[test]
+    fn removal_and_absence_end_a_membership() {
+        let member = ParticipantId::generate();
+        assert!(!updated(member, false).ends_membership(member));
+        assert!(updated(member, true).ends_membership(member));
+        assert!(updated(ParticipantId::generate(), false).ends_membership(member));
+    }
+
+    #[test]
+    fn other_kinds_never_end_a_membership() {
+        let member = ParticipantId::generate();
+        let mut event = updated(member, true);
+        event.kind = EventKind::ConversationRead;
+        assert!(!event.ends_membership(member));
+    }
+}
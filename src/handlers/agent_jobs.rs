@@ -0,0 +1,378 @@
+use actix_web::{get, post, web, HttpResponse, Responder};
+use bson::{doc, Bson, DateTime as BsonDateTime};
+use futures::TryStreamExt;
+use mongodb::{
+    options::{FindOptions, ReturnDocument},
+    ClientSession,
+    Database,
+};
+use serde::Deserialize;
+
+use crate::error::{AppError, AppResult};
+use crate::events::{Event, EventBus, EventKind};
+use crate::ids::{AgentJobId, MessageId, ParticipantId};
+use crate::models::{
+    AgentJob, AgentJobStatus, ConvRole, Conversation, Message, Participant, ParticipantType,
+};
+use crate::transactions;
+
+const DEFAULT_LEASE_SECS: u64 = 60;
+const MAX_LEASE_SECS: u64 = 3600;
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Deserialize)]
+pub struct ClaimAgentJobPayload {
+    pub worker_id: String,
+    /// Restricts claiming to the jobs of one AI participant.
+    pub participant_id: Option<ParticipantId>,
+    pub lease_seconds: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct HeartbeatAgentJobPayload {
+    pub worker_id: String,
+    pub lease_seconds: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct CompleteAgentJobPayload {
+    pub worker_id: String,
+    pub result_message_id: Option<MessageId>,
+}
+
+#[derive(Deserialize)]
+pub struct FailAgentJobPayload {
+    pub worker_id: String,
+    pub error: String,
+    #[serde(default = "default_retry")]
+    pub retry: bool,
+}
+
+fn default_retry() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct AgentJobListQuery {
+    pub status: Option<AgentJobStatus>,
+    pub participant_id: Option<ParticipantId>,
+}
+
+fn lease_until(lease_seconds: Option<u64>) -> BsonDateTime {
+    let secs = lease_seconds.unwrap_or(DEFAULT_LEASE_SECS).clamp(1, MAX_LEASE_SECS);
+    BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() + secs as i64 * 1000)
+}
+
+/// Job events reach the AI participant the job belongs to; the conversation
+/// id lets conversation streams show that a reply is being worked on.
+async fn record_job(
+    events: &EventBus,
+    session: &mut ClientSession,
+    kind: EventKind,
+    job: &AgentJob,
+) -> mongodb::error::Result<Event> {
+    events.record(session, kind, Some(job.conversation_id), vec![job.participant_id], job).await
+}
+
+/// Builds one reply job per active AI member of the conversation when a
+/// human posts a message. Observers never get asked to reply. The caller
+/// stores the jobs together with the message and records them with
+/// [`record_created_jobs`].
+pub(crate) async fn reply_jobs_for(
+    db: &Database,
+    conv: &Conversation,
+    sender: &Participant,
+    msg: &Message,
+) -> mongodb::error::Result<Vec<AgentJob>> {
+    if !matches!(sender.participant_type, ParticipantType::Human) {
+        return Ok(Vec::new());
+    }
+
+    let candidate_ids: Vec<Bson> = conv.participants.iter()
+        .filter(|cp| cp.left_at.is_none() && cp.role != ConvRole::Observer)
+        .map(|cp| Bson::String(cp.participant_id.to_string()))
+        .collect();
+    if candidate_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut cursor = db.collection::<Participant>("participants")
+        .find(doc! { "_id": { "$in": candidate_ids }, "type": "ai" })
+        .await?;
+
+    let now = BsonDateTime::now();
+    let mut jobs = Vec::new();
+    while let Some(bot) = cursor.try_next().await? {
+        jobs.push(AgentJob {
+            id: AgentJobId::generate(),
+            participant_id: bot.id,
+            conversation_id: conv.id,
+            trigger_message_id: msg.id,
+            status: AgentJobStatus::Pending,
+            attempts: 0,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            worker_id: None,
+            lease_expires_at: None,
+            last_error: None,
+            result_message_id: None,
+            created_at: now,
+            updated_at: now,
+        });
+    }
+
+    Ok(jobs)
+}
+
+/// Appends each event to `recorded` as soon as it is written, so a caller
+/// undoing a failed write knows which ones to remove.
+pub(crate) async fn record_created_jobs(
+    events: &EventBus,
+    session: &mut ClientSession,
+    jobs: &[AgentJob],
+    recorded: &mut Vec<Event>,
+) -> mongodb::error::Result<()> {
+    for job in jobs {
+        recorded.push(record_job(events, session, EventKind::AgentJobCreated, job).await?);
+    }
+    Ok(())
+}
+
+/// Hands the oldest available job to a worker. A job is available when it is
+/// pending or its previous worker let the lease expire; responds with 204
+/// when there is nothing to do.
+#[post("/agent-jobs/claim")]
+pub async fn claim_agent_job(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    payload: web::Json<ClaimAgentJobPayload>,
+) -> AppResult<impl Responder> {
+    let p = payload.into_inner();
+    let job_coll = db.collection::<AgentJob>("agent_jobs");
+    let now = BsonDateTime::now();
+
+    // Expired leases that used up their attempts are not handed out again.
+    // The sweep is not logged per job; consumers see the final state through
+    // `GET /agent-jobs`.
+    job_coll
+        .update_many(
+            doc! {
+                "status": "claimed",
+                "lease_expires_at": { "$lt": now },
+                "$expr": { "$gte": ["$attempts", "$max_attempts"] }
+            },
+            doc! { "$set": {
+                "status": "failed",
+                "worker_id": null,
+                "lease_expires_at": null,
+                "last_error": "Lease expired on the last attempt",
+                "updated_at": now
+            } },
+        )
+        .await?;
+
+    let mut filter = doc! {
+        "$or": [
+            { "status": "pending" },
+            { "status": "claimed", "lease_expires_at": { "$lt": now } }
+        ]
+    };
+    if let Some(participant_id) = &p.participant_id {
+        filter.insert("participant_id", *participant_id);
+    }
+
+    let claimed = transactions::run(&db, async |session| {
+        let job = job_coll
+            .find_one_and_update(
+                filter.clone(),
+                doc! {
+                    "$set": {
+                        "status": "claimed",
+                        "worker_id": &p.worker_id,
+                        "lease_expires_at": lease_until(p.lease_seconds),
+                        "updated_at": now
+                    },
+                    "$inc": { "attempts": 1 }
+                },
+            )
+            .sort(doc! { "created_at": 1 })
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?;
+
+        let Some(job) = job else { return Ok(None) };
+        let event = record_job(&events, session, EventKind::AgentJobUpdated, &job)
+            .await?;
+        Ok(Some((job, event)))
+    })
+    .await?;
+
+    let Some((job, event)) = claimed else {
+        return Ok(HttpResponse::NoContent().finish());
+    };
+    events.announce(event);
+
+    Ok(HttpResponse::Ok().json(job))
+}
+
+/// Extends the lease. Renewals are not state changes and record no event, so
+/// they stay off the event sequence that every logged write competes for.
+#[post("/agent-jobs/{id}/heartbeat")]
+pub async fn heartbeat_agent_job(
+    db: web::Data<Database>,
+    path: web::Path<AgentJobId>,
+    payload: web::Json<HeartbeatAgentJobPayload>,
+) -> AppResult<impl Responder> {
+    let job_id = path.into_inner();
+    let p = payload.into_inner();
+    let job_coll = db.collection::<AgentJob>("agent_jobs");
+
+    let job = job_coll
+        .find_one_and_update(
+            doc! { "_id": job_id.to_string(), "status": "claimed", "worker_id": &p.worker_id },
+            doc! { "$set": {
+                "lease_expires_at": lease_until(p.lease_seconds),
+                "updated_at": BsonDateTime::now()
+            } },
+        )
+        .return_document(ReturnDocument::After)
+        .await?
+        .ok_or_else(|| AppError::conflict("job_not_claimed", "Job is not claimed by this worker"))?;
+
+    Ok(HttpResponse::Ok().json(job))
+}
+
+#[post("/agent-jobs/{id}/complete")]
+pub async fn complete_agent_job(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    path: web::Path<AgentJobId>,
+    payload: web::Json<CompleteAgentJobPayload>,
+) -> AppResult<impl Responder> {
+    let job_id = path.into_inner();
+    let p = payload.into_inner();
+    let job_coll = db.collection::<AgentJob>("agent_jobs");
+
+    let (job, event) = transactions::run(&db, async |session| {
+        let job = job_coll
+            .find_one_and_update(
+                doc! { "_id": job_id.to_string(), "status": "claimed", "worker_id": &p.worker_id },
+                doc! { "$set": {
+                    "status": "completed",
+                    "lease_expires_at": null,
+                    "result_message_id": p.result_message_id.map(|id| id.to_string()),
+                    "updated_at": BsonDateTime::now()
+                } },
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| AppError::conflict("job_not_claimed", "Job is not claimed by this worker"))?;
+
+        let event = record_job(&events, session, EventKind::AgentJobUpdated, &job)
+            .await?;
+        Ok((job, event))
+    })
+    .await?;
+    events.announce(event);
+
+    Ok(HttpResponse::Ok().json(job))
+}
+
+/// Records a failed attempt. The job goes back to the queue unless the worker
+/// asked not to retry or the attempts are used up.
+#[post("/agent-jobs/{id}/fail")]
+pub async fn fail_agent_job(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    path: web::Path<AgentJobId>,
+    payload: web::Json<FailAgentJobPayload>,
+) -> AppResult<impl Responder> {
+    let job_id = path.into_inner();
+    let p = payload.into_inner();
+    let job_coll = db.collection::<AgentJob>("agent_jobs");
+
+    let next_status = if p.retry {
+        doc! { "$cond": [{ "$lt": ["$attempts", "$max_attempts"] }, "pending", "failed"] }
+    } else {
+        doc! { "$literal": "failed" }
+    };
+
+    let (job, event) = transactions::run(&db, async |session| {
+        let job = job_coll
+            .find_one_and_update(
+                doc! { "_id": job_id.to_string(), "status": "claimed", "worker_id": &p.worker_id },
+                vec![doc! { "$set": {
+                    "status": next_status.clone(),
+                    "worker_id": null,
+                    "lease_expires_at": null,
+                    "last_error": { "$literal": &p.error },
+                    "updated_at": BsonDateTime::now()
+                } }],
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| AppError::conflict("job_not_claimed", "Job is not claimed by this worker"))?;
+
+        let event = record_job(&events, session, EventKind::AgentJobUpdated, &job)
+            .await?;
+        Ok((job, event))
+    })
+    .await?;
+    events.announce(event);
+
+    Ok(HttpResponse::Ok().json(job))
+}
+
+#[get("/agent-jobs")]
+pub async fn get_agent_jobs(
+    db: web::Data<Database>,
+    query: web::Query<AgentJobListQuery>,
+) -> AppResult<impl Responder> {
+    let q = query.into_inner();
+    let job_coll = db.collection::<AgentJob>("agent_jobs");
+
+    let mut filter = doc! {};
+    if let Some(status) = q.status {
+        let status_bson = bson::to_bson(&status)?;
+        filter.insert("status", status_bson);
+    }
+    if let Some(participant_id) = q.participant_id {
+        filter.insert("participant_id", participant_id);
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc! { "created_at": -1 })
+        .build();
+
+    let mut cursor = job_coll
+        .find(filter)
+        .with_options(options)
+        .await?;
+
+    let mut jobs = Vec::new();
+    while let Some(j) = cursor
+        .try_next()
+        .await?
+    {
+        jobs.push(j);
+    }
+
+    Ok(HttpResponse::Ok().json(jobs))
+}
+
+#[get("/agent-jobs/{id}")]
+pub async fn get_agent_job(
+    db: web::Data<Database>,
+    path: web::Path<AgentJobId>,
+) -> AppResult<impl Responder> {
+    let job_id = path.into_inner();
+    let job_coll = db.collection::<AgentJob>("agent_jobs");
+
+    let job = job_coll
+        .find_one(doc! { "_id": job_id.to_string() })
+        .await?
+        .ok_or_else(|| AppError::not_found("agent_job_not_found", "Agent job not found"))?;
+
+    Ok(HttpResponse::Ok().json(job))
+}
@@ -0,0 +1,212 @@
+use actix_web::{get, put, web, HttpResponse, Responder};
+use bson::{doc, Bson, DateTime as BsonDateTime};
+use futures::TryStreamExt;
+use mongodb::{
+    options::{FindOptions, ReturnDocument},
+    Database,
+};
+use serde::Deserialize;
+use validator::Validate;
+
+use crate::error::{AppError, AppResult};
+use crate::events::{EventBus, EventKind};
+use crate::ids::{AiConfigVersionId, ParticipantId};
+use crate::models::{AiConfig, AiConfigVersion, Participant, ParticipantType};
+use crate::transactions;
+use crate::validation::{self, MAX_AI_TOOLS, MAX_CONTENT_CHARS, MAX_NAME_CHARS};
+
+const MAX_TEMPERATURE: f64 = 2.0;
+
+#[derive(Deserialize, Validate)]
+pub struct UpdateAiConfigPayload {
+    #[validate(custom(function = validation::not_blank), length(max = MAX_NAME_CHARS))]
+    pub provider: String,
+    #[validate(custom(function = validation::not_blank), length(max = MAX_NAME_CHARS))]
+    pub model: String,
+    #[validate(length(max = MAX_CONTENT_CHARS))]
+    pub system_prompt: Option<String>,
+    #[validate(range(min = 0.0, max = MAX_TEMPERATURE))]
+    pub temperature: Option<f64>,
+    #[validate(range(min = 1))]
+    pub max_context_tokens: Option<u32>,
+    #[serde(default)]
+    #[validate(length(max = MAX_AI_TOOLS), custom(function = validation::names))]
+    pub tools: Vec<String>,
+}
+
+/// Replaces the model configuration of an AI participant. Every change gets
+/// a new version number and a copy in `ai_config_versions`, so consumers can
+/// tell which settings produced a given reply.
+#[put("/participants/{id}/ai-config")]
+pub async fn update_ai_config(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    path: web::Path<ParticipantId>,
+    payload: web::Json<UpdateAiConfigPayload>,
+) -> AppResult<impl Responder> {
+    let part_id = path.into_inner();
+    let p = payload.into_inner();
+    p.validate()?;
+    let part_coll = db.collection::<Participant>("participants");
+    let version_coll = db.collection::<AiConfigVersion>("ai_config_versions");
+
+    let part = part_coll
+        .find_one(doc! { "_id": &part_id })
+        .await?
+        .ok_or_else(|| AppError::not_found("participant_not_found", "Participant not found"))?;
+
+    if !matches!(part.participant_type, ParticipantType::Ai) {
+        return Err(AppError::bad_request(
+            "not_an_ai_participant",
+            "Only AI participants carry a model configuration"
+        ));
+    }
+
+    let current_version = part.ai_config.as_ref().map(|c| c.version);
+    let config = AiConfig {
+        version: current_version.unwrap_or(0) + 1,
+        provider: p.provider,
+        model: p.model,
+        system_prompt: p.system_prompt,
+        temperature: p.temperature,
+        max_context_tokens: p.max_context_tokens,
+        tools: p.tools,
+        updated_at: BsonDateTime::now(),
+    };
+
+    let config_bson = bson::to_bson(&config)?;
+
+    // Only swap the config if nobody else bumped the version in between.
+    let version_filter = match current_version {
+        Some(v) => Bson::from(v),
+        None => Bson::Null,
+    };
+
+    let (part, event) = transactions::run(&db, async |session| {
+        let part = part_coll
+            .find_one_and_update(
+                doc! { "_id": &part_id, "ai_config.version": version_filter.clone() },
+                doc! { "$set": { "ai_config": config_bson.clone() } },
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| {
+                AppError::conflict("concurrent_modification", "AI config was changed concurrently, retry the request")
+            })?;
+
+        version_coll
+            .insert_one(&AiConfigVersion {
+                id: AiConfigVersionId::generate(),
+                participant_id: part_id,
+                config: config.clone(),
+            })
+            .session(&mut *session)
+            .await?;
+
+        let event = events.record_for_participants(session, EventKind::ParticipantUpdated, &[part.id], &part)
+            .await?;
+        Ok((part, event))
+    })
+    .await?;
+    events.announce(event);
+
+    Ok(HttpResponse::Ok().json(part))
+}
+
+#[get("/participants/{id}/ai-config")]
+pub async fn get_ai_config(
+    db: web::Data<Database>,
+    path: web::Path<ParticipantId>,
+) -> AppResult<impl Responder> {
+    let part_id = path.into_inner();
+    let part_coll = db.collection::<Participant>("participants");
+
+    let config = part_coll
+        .find_one(doc! { "_id": &part_id })
+        .await?
+        .ok_or_else(|| AppError::not_found("participant_not_found", "Participant not found"))?
+        .ai_config
+        .ok_or_else(|| AppError::not_found("ai_config_not_found", "Participant has no AI config"))?;
+
+    Ok(HttpResponse::Ok().json(config))
+}
+
+#[get("/participants/{id}/ai-config/versions")]
+pub async fn get_ai_config_versions(
+    db: web::Data<Database>,
+    path: web::Path<ParticipantId>,
+) -> AppResult<impl Responder> {
+    let part_id = path.into_inner();
+    let version_coll = db.collection::<AiConfigVersion>("ai_config_versions");
+
+    let options = FindOptions::builder()
+        .sort(doc! { "config.version": -1 })
+        .build();
+
+    let mut cursor = version_coll
+        .find(doc! { "participant_id": &part_id })
+        .with_options(options)
+        .await?;
+
+    let mut versions = Vec::new();
+    while let Some(v) = cursor
+        .try_next()
+        .await?
+    {
+        versions.push(v.config);
+    }
+
+    Ok(HttpResponse::Ok().json(versions))
+}
+
+#[get("/participants/{id}/ai-config/versions/{version}")]
+pub async fn get_ai_config_version(
+    db: web::Data<Database>,
+    path: web::Path<(ParticipantId, u32)>,
+) -> AppResult<impl Responder> {
+    let (part_id, version) = path.into_inner();
+    let version_coll = db.collection::<AiConfigVersion>("ai_config_versions");
+
+    let v = version_coll
+        .find_one(doc! { "participant_id": &part_id, "config.version": version })
+        .await?
+        .ok_or_else(|| AppError::not_found("ai_config_version_not_found", "AI config version not found"))?;
+
+    Ok(HttpResponse::Ok().json(v.config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> UpdateAiConfigPayload {
+        UpdateAiConfigPayload {
+            provider: "openai".to_string(),
+            model: "gpt".to_string(),
+            system_prompt: None,
+            temperature: Some(0.7),
+            max_context_tokens: Some(8192),
+            tools: vec!["search".to_string()],
+        }
+    }
+
+    fn rejected(p: UpdateAiConfigPayload) -> Vec<String> {
+        let AppError::Validation(errors) = AppError::from(p.validate().unwrap_err()) else { unreachable!() };
+        errors.into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_settings() {
+        assert!(payload().validate().is_ok());
+        let p = UpdateAiConfigPayload {
+            model: " ".to_string(),
+            system_prompt: Some("x".repeat(MAX_CONTENT_CHARS as usize + 1)),
+            temperature: Some(2.5),
+            max_context_tokens: Some(0),
+            tools: vec![String::new()],
+            ..payload()
+        };
+        assert_eq!(rejected(p), ["max_context_tokens", "model", "system_prompt", "temperature", "tools"]);
+    }
+}
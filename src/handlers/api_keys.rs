@@ -0,0 +1,110 @@
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use bson::{doc, DateTime as BsonDateTime};
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::auth;
+use crate::error::{AppError, AppResult};
+use crate::ids::ApiKeyId;
+use crate::models::{ApiKey, Scope};
+use crate::validation::{self, MAX_NAME_CHARS};
+
+#[derive(Deserialize, Validate)]
+pub struct CreateApiKeyPayload {
+    #[validate(custom(function = validation::not_blank), length(max = MAX_NAME_CHARS))]
+    pub name: String,
+    #[validate(length(min = 1))]
+    pub scopes: Vec<Scope>,
+}
+
+/// A key as listed; the hash stays in the database.
+#[derive(Serialize)]
+pub struct ApiKeyView {
+    #[serde(rename = "_id")]
+    pub id: ApiKeyId,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: BsonDateTime,
+    pub last_used_at: Option<BsonDateTime>,
+    pub revoked_at: Option<BsonDateTime>,
+}
+
+impl From<ApiKey> for ApiKeyView {
+    fn from(key: ApiKey) -> Self {
+        ApiKeyView {
+            id: key.id,
+            name: key.name,
+            prefix: key.prefix,
+            scopes: key.scopes,
+            created_at: key.created_at,
+            last_used_at: key.last_used_at,
+            revoked_at: key.revoked_at,
+        }
+    }
+}
+
+/// A new key, the only response carrying the key itself.
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKeyView,
+    pub key: String,
+}
+
+#[post("/api-keys")]
+pub async fn create_api_key(
+    db: web::Data<Database>,
+    payload: web::Json<CreateApiKeyPayload>,
+) -> AppResult<impl Responder> {
+    let p = payload.into_inner();
+    p.validate()?;
+
+    let (api_key, key) = auth::create_key(&db, p.name.trim().to_string(), p.scopes).await?;
+
+    Ok(HttpResponse::Created().json(CreatedApiKey { api_key: api_key.into(), key }))
+}
+
+#[get("/api-keys")]
+pub async fn get_api_keys(
+    db: web::Data<Database>,
+) -> AppResult<impl Responder> {
+    let keys: Vec<ApiKeyView> = auth::list_keys(&db)
+        .await?
+        .into_iter()
+        .map(ApiKeyView::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(keys))
+}
+
+#[get("/api-keys/{id}")]
+pub async fn get_api_key(
+    db: web::Data<Database>,
+    path: web::Path<ApiKeyId>,
+) -> AppResult<impl Responder> {
+    let key_id = path.into_inner();
+
+    let key = db.collection::<ApiKey>("api_keys")
+        .find_one(doc! { "_id": key_id })
+        .await?
+        .ok_or_else(|| AppError::not_found("api_key_not_found", "API key not found"))?;
+
+    Ok(HttpResponse::Ok().json(ApiKeyView::from(key)))
+}
+
+/// Revokes the key; it stays listed with `revoked_at` set.
+#[delete("/api-keys/{id}")]
+pub async fn revoke_api_key(
+    db: web::Data<Database>,
+    path: web::Path<ApiKeyId>,
+) -> AppResult<impl Responder> {
+    let key_id = path.into_inner();
+
+    let key = auth::revoke_key(&db, key_id)
+        .await?
+        .ok_or_else(|| AppError::not_found("api_key_not_found", "API key not found"))?;
+
+    Ok(HttpResponse::Ok().json(ApiKeyView::from(key)))
+}
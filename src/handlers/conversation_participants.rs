@@ -0,0 +1,194 @@
+use actix_web::{delete, post, put, web, HttpResponse, Responder};
+use bson::{doc, DateTime as BsonDateTime};
+use mongodb::{
+    options::ReturnDocument,
+    Database,
+};
+use serde::Deserialize;
+
+use crate::error::{AppError, AppResult};
+use crate::events::{EventBus, EventKind};
+use crate::ids::{ConversationId, ParticipantId};
+use crate::models::{ConvRole, Conversation, Participant};
+use crate::transactions;
+
+#[derive(Deserialize)]
+pub struct AddConversationParticipantPayload {
+    pub participant_id: ParticipantId,
+    pub role: Option<ConvRole>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateConversationParticipantRolePayload {
+    pub role: ConvRole,
+}
+
+#[post("/conversations/{id}/participants")]
+pub async fn add_conversation_participant(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    path: web::Path<ConversationId>,
+    payload: web::Json<AddConversationParticipantPayload>,
+) -> AppResult<impl Responder> {
+    let conv_id = path.into_inner();
+    let p = payload.into_inner();
+    let conv_coll = db.collection::<Conversation>("conversations");
+    let part_coll = db.collection::<Participant>("participants");
+
+    let conv_id_str = conv_id.to_string();
+    let part_id_str = p.participant_id.to_string();
+
+    let conv = conv_coll
+        .find_one(doc! { "_id": &conv_id_str })
+        .await?
+        .ok_or_else(|| AppError::not_found("conversation_not_found", "Conversation not found"))?;
+
+    let part = part_coll
+        .find_one(doc! { "_id": &part_id_str })
+        .await?
+        .ok_or_else(|| AppError::not_found("participant_not_found", "Participant not found"))?;
+
+    let role = p.role.unwrap_or_else(|| ConvRole::default_for(&part.participant_type));
+    let role_bson = bson::to_bson(&role)?;
+    let now = BsonDateTime::now();
+
+    // Both updates are guarded by the membership state they expect, so a
+    // concurrent join or leave turns into a conflict instead of a duplicate entry.
+    let rejoining = match conv.membership(&p.participant_id) {
+        Some(cp) if cp.left_at.is_none() => {
+            return Err(AppError::conflict(
+                "already_member",
+                "Participant is already a member of this conversation"
+            ));
+        }
+        Some(_) => true,
+        None => false,
+    };
+
+    let (conv, event) = transactions::run(&db, async |session| {
+        let updated = if rejoining {
+            conv_coll
+                .find_one_and_update(
+                    doc! {
+                        "_id": &conv_id_str,
+                        "participants": { "$elemMatch": {
+                            "participant_id": &part_id_str,
+                            "left_at": { "$ne": null }
+                        } }
+                    },
+                    doc! { "$set": {
+                        "participants.$.role": role_bson.clone(),
+                        "participants.$.joined_at": now,
+                        "participants.$.left_at": null
+                    } },
+                )
+                .return_document(ReturnDocument::After)
+                .session(&mut *session)
+                .await?
+        } else {
+            conv_coll
+                .find_one_and_update(
+                    doc! {
+                        "_id": &conv_id_str,
+                        "participants.participant_id": { "$ne": &part_id_str }
+                    },
+                    doc! { "$push": { "participants": {
+                        "participant_id": &part_id_str,
+                        "role": role_bson.clone(),
+                        "joined_at": now,
+                        "left_at": null
+                    } } },
+                )
+                .return_document(ReturnDocument::After)
+                .session(&mut *session)
+                .await?
+        };
+
+        let conv = updated.ok_or_else(|| {
+            AppError::conflict("concurrent_modification", "Membership changed concurrently, retry the request")
+        })?;
+
+        let event = events.record_for(session, EventKind::ConversationUpdated, &conv, &[], &conv)
+            .await?;
+        Ok((conv, event))
+    })
+    .await?;
+    events.announce(event);
+
+    Ok(HttpResponse::Ok().json(conv))
+}
+
+#[delete("/conversations/{id}/participants/{participant_id}")]
+pub async fn remove_conversation_participant(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    path: web::Path<(ConversationId, ParticipantId)>,
+) -> AppResult<impl Responder> {
+    let (conv_id, part_id) = path.into_inner();
+    let conv_coll = db.collection::<Conversation>("conversations");
+
+    let (conv, event) = transactions::run(&db, async |session| {
+        // The entry is kept with left_at set so history and read cursors survive.
+        let conv = conv_coll
+            .find_one_and_update(
+                doc! {
+                    "_id": conv_id.to_string(),
+                    "participants": { "$elemMatch": {
+                        "participant_id": part_id.to_string(),
+                        "left_at": null
+                    } }
+                },
+                doc! { "$set": { "participants.$.left_at": BsonDateTime::now() } },
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| AppError::not_found("membership_not_found", "Active conversation membership not found"))?;
+
+        // The removed participant is told as well.
+        let event = events.record_for(session, EventKind::ConversationUpdated, &conv, &[part_id], &conv)
+            .await?;
+        Ok((conv, event))
+    })
+    .await?;
+    events.announce(event);
+
+    Ok(HttpResponse::Ok().json(conv))
+}
+
+#[put("/conversations/{id}/participants/{participant_id}/role")]
+pub async fn update_conversation_participant_role(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    path: web::Path<(ConversationId, ParticipantId)>,
+    payload: web::Json<UpdateConversationParticipantRolePayload>,
+) -> AppResult<impl Responder> {
+    let (conv_id, part_id) = path.into_inner();
+    let p = payload.into_inner();
+    let conv_coll = db.collection::<Conversation>("conversations");
+
+    let role_bson = bson::to_bson(&p.role)?;
+
+    let (conv, event) = transactions::run(&db, async |session| {
+        let conv = conv_coll
+            .find_one_and_update(
+                doc! {
+                    "_id": conv_id.to_string(),
+                    "participants.participant_id": part_id.to_string()
+                },
+                doc! { "$set": { "participants.$.role": role_bson.clone() } },
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| AppError::not_found("membership_not_found", "Conversation membership not found"))?;
+
+        let event = events.record_for(session, EventKind::ConversationUpdated, &conv, &[], &conv)
+            .await?;
+        Ok((conv, event))
+    })
+    .await?;
+    events.announce(event);
+
+    Ok(HttpResponse::Ok().json(conv))
+}
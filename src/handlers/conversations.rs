@@ -1,5 +1,5 @@
 use actix_web::{get, post, put, web, HttpResponse, Responder};
-use bson::{doc, Bson, DateTime as BsonDateTime};
+use bson::{doc, Bson, DateTime as BsonDateTime, Document};
 use futures::TryStreamExt;
 use mongodb::{
     options::{FindOptions, ReturnDocument},
@@ -7,75 +7,148 @@ use mongodb::{
 };
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use validator::Validate;
 
+use crate::auth::{self, Principal};
+use crate::error::{AppError, AppResult};
+use crate::events::{EventBus, EventKind};
+use crate::ids::ConversationId;
 use crate::models::{Conversation, Participant, Message};
+use crate::transactions;
+use crate::validation::{MAX_TEXT_CHARS, MAX_TOPIC_CHARS};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct CreateConversationPayload {
     pub external_id: Uuid,
+    #[validate(length(max = MAX_TOPIC_CHARS))]
     pub topic: Option<String>,
+    #[serde(default)]
+    pub allow_departed_senders: bool,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ConversationSort {
+    #[default]
+    StartedAt,
+    LastActivity,
+}
+
+impl ConversationSort {
+    /// Aggregation stages putting the most recent conversations first. For
+    /// last activity, conversations without messages count from their start
+    /// time; sorting on `last_message_at` alone would rank them below every
+    /// conversation with a message, since a missing value sorts lowest.
+    pub fn stages(self) -> Vec<Document> {
+        match self {
+            ConversationSort::StartedAt => vec![doc! { "$sort": { "started_at": -1 } }],
+            ConversationSort::LastActivity => vec![
+                doc! { "$set": { "last_activity_at": { "$ifNull": ["$last_message_at", "$started_at"] } } },
+                doc! { "$sort": { "last_activity_at": -1, "started_at": -1 } },
+                doc! { "$unset": "last_activity_at" },
+            ],
+        }
+    }
 }
 
 #[derive(Deserialize)]
+pub struct ConversationListQuery {
+    pub sort: Option<ConversationSort>,
+}
+
+#[derive(Deserialize, Validate)]
 pub struct UpdateConversationMetadataPayload {
+    #[validate(length(max = MAX_TEXT_CHARS))]
     pub summary: Option<String>,
+    #[validate(length(max = MAX_TEXT_CHARS))]
     pub context: Option<String>,
 }
 
 #[post("/conversations")]
 pub async fn create_conversation(
     db: web::Data<Database>,
+    events: web::Data<EventBus>,
     payload: web::Json<CreateConversationPayload>,
-) -> actix_web::Result<impl Responder> {
+) -> AppResult<impl Responder> {
     let p = payload.into_inner();
+    p.validate()?;
+    let conv = find_or_create_conversation(&db, &events, p).await?;
+
+    Ok(HttpResponse::Ok().json(conv))
+}
+
+/// Returns the conversation with the given external id, creating it first if
+/// needed. Shared with batch ingestion, which creates conversations on the fly.
+pub(crate) async fn find_or_create_conversation(
+    db: &Database,
+    events: &EventBus,
+    p: CreateConversationPayload,
+) -> AppResult<Conversation> {
     let conv_coll = db.collection::<Conversation>("conversations");
 
     let ext_id_str = p.external_id.to_string();
     let now = BsonDateTime::now();
+    let new_id = ConversationId::generate();
 
-    let conv = conv_coll
-        .find_one_and_update(
-            doc! { "external_id": &ext_id_str },
-            doc! {
-              "$setOnInsert": {
-                "_id": Uuid::new_v4().to_string(),
-                "external_id": &ext_id_str,
-                "topic": &p.topic.map(Bson::String).unwrap_or(Bson::Null),
-                "started_at": &now,
-                "participants": []
-              }
-            },
-        )
-        .upsert(true)
-        .return_document(ReturnDocument::After)
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
-        .expect("just inserted or found");
+    let topic = p.topic.map(Bson::String).unwrap_or(Bson::Null);
 
-    Ok(HttpResponse::Ok().json(conv))
+    let (conv, event) = transactions::run(db, async |session| {
+        let conv = conv_coll
+            .find_one_and_update(
+                doc! { "external_id": &ext_id_str },
+                doc! {
+                  "$setOnInsert": {
+                    "_id": new_id,
+                    "external_id": &ext_id_str,
+                    "topic": &topic,
+                    "started_at": &now,
+                    "last_message_at": Bson::Null,
+                    "last_message_id": Bson::Null,
+                    "message_count": 0_i64,
+                    "channel_counts": {},
+                    "participants": [],
+                    "allow_departed_senders": p.allow_departed_senders
+                  }
+                },
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| AppError::Internal("upserted conversation not returned".to_string()))?;
+
+        // An existing conversation keeps its id, so a match means it was just created.
+        let event = if conv.id == new_id {
+            Some(events.record_for(session, EventKind::ConversationCreated, &conv, &[], &conv).await?)
+        } else {
+            None
+        };
+        Ok((conv, event))
+    })
+    .await?;
+    if let Some(event) = event {
+        events.announce(event);
+    }
+
+    Ok(conv)
 }
 
 #[get("/conversations")]
 pub async fn get_all_conversations(
     db: web::Data<Database>,
-) -> actix_web::Result<impl Responder> {
+    query: web::Query<ConversationListQuery>,
+) -> AppResult<impl Responder> {
     let conv_coll = db.collection::<Conversation>("conversations");
 
-    let options = FindOptions::builder()
-        .sort(doc! { "started_at": -1 })
-        .build();
-
     let mut cursor = conv_coll
-        .find(doc! {})
-        .with_options(options)
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+        .aggregate(query.sort.unwrap_or_default().stages())
+        .with_type::<Conversation>()
+        .await?;
 
     let mut conversations = Vec::new();
     while let Some(c) = cursor
         .try_next()
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
+        .await?
     {
         conversations.push(c);
     }
@@ -86,8 +159,9 @@ pub async fn get_all_conversations(
 #[get("/conversations/{id}")]
 pub async fn get_conversation(
     db: web::Data<Database>,
-    path: web::Path<Uuid>,
-) -> actix_web::Result<impl Responder> {
+    principal: Option<web::ReqData<Principal>>,
+    path: web::Path<ConversationId>,
+) -> AppResult<impl Responder> {
     let conv_id = path.into_inner();
     let conv_coll = db.collection::<Conversation>("conversations");
     let part_coll = db.collection::<Participant>("participants");
@@ -97,9 +171,9 @@ pub async fn get_conversation(
 
     let conv = conv_coll
         .find_one(doc! { "_id": &conv_id_str })
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
-        .ok_or_else(|| actix_web::error::ErrorNotFound("Conversation not found"))?;
+        .await?
+        .ok_or_else(|| AppError::not_found("conversation_not_found", "Conversation not found"))?;
+    auth::ensure_member(principal.map(web::ReqData::into_inner), &conv)?;
 
     let ids: Vec<String> = conv.participants.iter()
         .map(|cp| cp.participant_id.to_string())
@@ -109,14 +183,12 @@ pub async fn get_conversation(
 
     let mut cursor = part_coll
         .find(doc! { "_id": { "$in": Bson::Array(id_array) } })
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+        .await?;
 
     let mut parts = Vec::new();
     while let Some(p) = cursor
         .try_next()
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
+        .await?
     {
         parts.push(p);
     }
@@ -128,14 +200,12 @@ pub async fn get_conversation(
     let mut mc = msg_coll
         .find(doc! { "conversation_id": &conv_id_str })
         .with_options(options)
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+        .await?;
 
     let mut msgs = Vec::new();
     while let Some(m) = mc
         .try_next()
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
+        .await?
     {
         msgs.push(m);
     }
@@ -157,11 +227,13 @@ pub async fn get_conversation(
 #[put("/conversations/{id}/metadata")]
 pub async fn update_conversation_metadata(
     db: web::Data<Database>,
-    path: web::Path<Uuid>,
+    events: web::Data<EventBus>,
+    path: web::Path<ConversationId>,
     payload: web::Json<UpdateConversationMetadataPayload>,
-) -> actix_web::Result<impl Responder> {
+) -> AppResult<impl Responder> {
     let conv_id = path.into_inner();
     let p = payload.into_inner();
+    p.validate()?;
     let conv_coll = db.collection::<Conversation>("conversations");
 
     let conv_id_str = conv_id.to_string();
@@ -174,15 +246,23 @@ pub async fn update_conversation_metadata(
         update_doc.insert("context", context);
     }
 
-    let conv = conv_coll
-        .find_one_and_update(
-            doc! { "_id": &conv_id_str },
-            doc! { "$set": update_doc },
-        )
-        .return_document(ReturnDocument::After)
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
-        .ok_or_else(|| actix_web::error::ErrorNotFound("Conversation not found"))?;
+    let (conv, event) = transactions::run(&db, async |session| {
+        let conv = conv_coll
+            .find_one_and_update(
+                doc! { "_id": &conv_id_str },
+                doc! { "$set": update_doc.clone() },
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| AppError::not_found("conversation_not_found", "Conversation not found"))?;
+
+        let event = events.record_for(session, EventKind::ConversationUpdated, &conv, &[], &conv)
+            .await?;
+        Ok((conv, event))
+    })
+    .await?;
+    events.announce(event);
 
     Ok(HttpResponse::Ok().json(conv))
 }
\ No newline at end of file
@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse, Responder};
+use bson::{doc, Document};
+use futures::stream;
+use mongodb::Database;
+use serde::Deserialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::auth::{self, Principal};
+use crate::error::{AppError, AppResult};
+use crate::events::{Event, EventBus};
+use crate::ids::{ConversationId, ParticipantId};
+use crate::models::{Conversation, Participant};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+const REPLAY_PAGE: i64 = 500;
+const DEFAULT_EVENT_PAGE: i64 = 100;
+const MAX_EVENT_PAGE: i64 = 1000;
+
+#[derive(Deserialize)]
+pub struct EventStreamQuery {
+    /// Fallback for clients that cannot set the `Last-Event-ID` header.
+    pub last_event_id: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct EventLogQuery {
+    /// Sequence number of the last event the consumer has seen, 0 for all.
+    #[serde(default)]
+    pub since: u64,
+    pub limit: Option<i64>,
+    pub conversation_id: Option<ConversationId>,
+    pub participant_id: Option<ParticipantId>,
+}
+
+struct StreamState<F, S> {
+    events: EventBus,
+    receiver: broadcast::Receiver<Event>,
+    backlog: VecDeque<Event>,
+    /// Set while the log may hold events the client has not seen.
+    replaying: bool,
+    /// Sequence number the stream has got to, including events that did not
+    /// match the filter.
+    last_id: u64,
+    /// Where the replayed page in `backlog` ends.
+    replayed_to: u64,
+    /// The same selection twice: as a query for replaying from the log and as
+    /// a predicate for live events.
+    log_filter: Document,
+    filter: F,
+    /// Ends the stream after an event it matches has been sent.
+    stop: S,
+    done: bool,
+}
+
+fn last_event_id(req: &HttpRequest, query: &EventStreamQuery) -> Option<u64> {
+    req.headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .or(query.last_event_id)
+}
+
+fn format_event(event: &Event) -> web::Bytes {
+    let data = serde_json::to_string(event).unwrap_or_else(|_| "null".into());
+    web::Bytes::from(format!("id: {}\nevent: {}\ndata: {}\n\n", event.id, event.kind.as_str(), data))
+}
+
+/// Builds the `text/event-stream` response. Resuming clients are first fed
+/// from the event log, then switch to live events; subscribing happens before
+/// the replay so nothing published in between is lost, and duplicates are
+/// skipped by sequence number. A live event that does not directly follow
+/// the last one, e.g. because another instance or a concurrent request wrote
+/// the one in between, sends the stream back to the log. The stream ends
+/// after the first event matching `stop`.
+async fn event_stream<F, S>(
+    events: &EventBus,
+    resume_from: Option<u64>,
+    log_filter: Document,
+    filter: F,
+    stop: S,
+) -> AppResult<HttpResponse>
+where
+    F: Fn(&Event) -> bool + 'static,
+    S: Fn(&Event) -> bool + 'static,
+{
+    let receiver = events.subscribe();
+    let head = events
+        .head()
+        .await?;
+
+    // Ids ahead of the log (e.g. after the database was reset) start live.
+    let last_id = resume_from.unwrap_or(head).min(head);
+
+    let state = StreamState {
+        events: events.clone(),
+        receiver,
+        backlog: VecDeque::new(),
+        replaying: last_id < head,
+        last_id,
+        replayed_to: last_id,
+        log_filter,
+        filter,
+        stop,
+        done: false,
+    };
+
+    let body = stream::unfold(state, |mut state| async move {
+        loop {
+            if state.done {
+                return None;
+            }
+            if let Some(event) = state.backlog.pop_front() {
+                if event.id <= state.last_id {
+                    continue;
+                }
+                state.last_id = event.id;
+                state.done = (state.stop)(&event);
+                let chunk = format_event(&event);
+                return Some((Ok::<_, actix_web::Error>(chunk), state));
+            }
+            state.last_id = state.last_id.max(state.replayed_to);
+
+            if state.replaying {
+                match state.events.since(state.last_id, state.log_filter.clone(), REPLAY_PAGE).await {
+                    Ok(page) => {
+                        // A short page reached the end of what can be read.
+                        state.replaying = page.events.len() as i64 >= REPLAY_PAGE;
+                        state.replayed_to = page.next_since;
+                        state.backlog.extend(page.events);
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to replay events: {e}");
+                        return None;
+                    }
+                }
+                continue;
+            }
+
+            match tokio::time::timeout(KEEP_ALIVE, state.receiver.recv()).await {
+                Ok(Ok(event)) if event.id <= state.last_id => {}
+                Ok(Ok(event)) if event.id == state.last_id + 1 => {
+                    state.last_id = event.id;
+                    if (state.filter)(&event) {
+                        state.done = (state.stop)(&event);
+                        return Some((Ok(format_event(&event)), state));
+                    }
+                }
+                // Missed the events in between or fell behind the live
+                // channel, catch up from the log.
+                Ok(Ok(_)) | Ok(Err(RecvError::Lagged(_))) => state.replaying = true,
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_) => {
+                    // Picks up events written elsewhere while it was quiet.
+                    state.replaying = true;
+                    return Some((Ok(web::Bytes::from_static(b": keep-alive\n\n")), state));
+                }
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/event-stream"))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(body))
+}
+
+#[get("/conversations/{id}/events")]
+pub async fn stream_conversation_events(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    principal: Option<web::ReqData<Principal>>,
+    req: HttpRequest,
+    path: web::Path<ConversationId>,
+    query: web::Query<EventStreamQuery>,
+) -> AppResult<impl Responder> {
+    let conv_id = path.into_inner();
+    let conv_coll = db.collection::<Conversation>("conversations");
+
+    let conv = conv_coll
+        .find_one(doc! { "_id": conv_id })
+        .await?
+        .ok_or_else(|| AppError::not_found("conversation_not_found", "Conversation not found"))?;
+    let principal = principal.map(web::ReqData::into_inner);
+    auth::ensure_member(principal, &conv)?;
+
+    // A participant's stream ends with the event removing them.
+    let member = principal.and_then(Principal::participant);
+    let resume_from = last_event_id(&req, &query);
+    event_stream(
+        &events,
+        resume_from,
+        doc! { "conversation_id": conv_id },
+        move |e| e.conversation_id == Some(conv_id),
+        move |e| member.is_some_and(|id| e.ends_membership(id)),
+    )
+    .await
+}
+
+#[get("/participants/{id}/events")]
+pub async fn stream_participant_events(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    req: HttpRequest,
+    path: web::Path<ParticipantId>,
+    query: web::Query<EventStreamQuery>,
+) -> AppResult<impl Responder> {
+    let part_id = path.into_inner();
+    let part_coll = db.collection::<Participant>("participants");
+
+    part_coll
+        .find_one(doc! { "_id": part_id })
+        .await?
+        .ok_or_else(|| AppError::not_found("participant_not_found", "Participant not found"))?;
+
+    let resume_from = last_event_id(&req, &query);
+    event_stream(
+        &events,
+        resume_from,
+        doc! { "participant_ids": part_id },
+        move |e| e.participant_ids.contains(&part_id),
+        |_| false,
+    )
+    .await
+}
+
+/// Incremental sync over the event log. Consumers keep `next_since` and poll
+/// with it; replaying from 0 rebuilds their state from scratch.
+#[get("/events")]
+pub async fn get_events(
+    events: web::Data<EventBus>,
+    query: web::Query<EventLogQuery>,
+) -> AppResult<impl Responder> {
+    let q = query.into_inner();
+    let limit = q.limit.unwrap_or(DEFAULT_EVENT_PAGE).clamp(1, MAX_EVENT_PAGE);
+
+    let mut filter = doc! {};
+    if let Some(conv_id) = q.conversation_id {
+        filter.insert("conversation_id", conv_id);
+    }
+    if let Some(part_id) = q.participant_id {
+        filter.insert("participant_ids", part_id);
+    }
+
+    let page = events
+        .since(q.since, filter, limit)
+        .await?;
+
+    Ok(HttpResponse::Ok().json(page))
+}
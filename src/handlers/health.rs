@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+use actix_web::{get, web, HttpResponse, Responder};
+use mongodb::Database;
+use serde::Serialize;
+
+use crate::health::{self, Health, Startup};
+
+/// Kept below the usual probe timeouts of orchestrators.
+const READY_PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub startup: Startup,
+    pub database: &'static str,
+}
+
+/// The process is up and serving HTTP, regardless of the database.
+#[get("/health/live")]
+pub async fn health_live() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Ready once startup has finished and the database answers right now.
+#[get("/health/ready")]
+pub async fn health_ready(
+    db: web::Data<Database>,
+    health: web::Data<Health>,
+) -> impl Responder {
+    let startup = health.startup();
+    let database_up = health::ping(&db, READY_PING_TIMEOUT).await.is_ok();
+    let ready = startup == Startup::Ready && database_up;
+
+    let body = Readiness { ready, startup, database: if database_up { "up" } else { "down" } };
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
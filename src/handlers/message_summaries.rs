@@ -3,65 +3,112 @@ use bson::{doc, Bson, DateTime as BsonDateTime};
 use futures::TryStreamExt;
 use mongodb::{
     options::FindOptions,
+    ClientSession,
     Database,
 };
 use serde::Deserialize;
-use uuid::Uuid;
+use validator::Validate;
 
-use crate::models::{Message, MessageSummary};
+use crate::auth::{self, Principal};
+use crate::error::{AppError, AppResult};
+use crate::events::{EventBus, EventKind};
+use crate::ids::{ConversationId, MessageId, MessageSummaryId};
+use crate::models::{Conversation, Message, MessageSummary};
+use crate::transactions;
+use crate::validation::{self, MAX_SUMMARY_MESSAGES, MAX_TEXT_CHARS};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct CreateMessageSummaryPayload {
-    pub conversation_id: Uuid,
-    pub message_ids: Vec<Uuid>,
+    pub conversation_id: ConversationId,
+    #[validate(length(min = 1, max = MAX_SUMMARY_MESSAGES))]
+    pub message_ids: Vec<MessageId>,
+    #[validate(custom(function = validation::not_blank), length(max = MAX_TEXT_CHARS))]
     pub summary: String,
+    #[validate(length(max = MAX_TEXT_CHARS))]
     pub context: Option<String>,
 }
 
 #[post("/message-summaries")]
 pub async fn create_message_summary(
     db: web::Data<Database>,
+    events: web::Data<EventBus>,
     payload: web::Json<CreateMessageSummaryPayload>,
-) -> actix_web::Result<impl Responder> {
+) -> AppResult<impl Responder> {
     let p = payload.into_inner();
+    p.validate()?;
+
+    // The summary and its event are stored together. The covered messages
+    // are only read, so nothing stops them from changing afterwards.
+    let (new_summary, event) = transactions::run(&db, async |session| {
+        let summary = write_summary(&db, session, &p).await?;
+
+        let conv = db.collection::<Conversation>("conversations")
+            .find_one(doc! { "_id": p.conversation_id.to_string() })
+            .session(&mut *session)
+            .await?;
+        let event = match &conv {
+            Some(conv) => events.record_for(session, EventKind::SummaryCreated, conv, &[], &summary).await,
+            None => events.record(session, EventKind::SummaryCreated, Some(p.conversation_id), vec![], &summary).await,
+        }?;
+        Ok((summary, event))
+    })
+    .await?;
+    events.announce(event);
+
+    Ok(HttpResponse::Ok().json(new_summary))
+}
+
+/// Validates the covered messages and stores the summary.
+async fn write_summary(
+    db: &Database,
+    session: &mut ClientSession,
+    p: &CreateMessageSummaryPayload,
+) -> AppResult<MessageSummary> {
     let msg_coll = db.collection::<Message>("messages");
     let summary_coll = db.collection::<MessageSummary>("message_summaries");
 
-    let msg_id_strs: Vec<String> = p.message_ids.iter().map(|id| id.to_string()).collect();
-    let msg_id_array: Vec<Bson> = msg_id_strs.iter().map(|id| Bson::String(id.clone())).collect();
+    let msg_id_array: Vec<Bson> = p.message_ids.iter().map(|id| Bson::String(id.to_string())).collect();
 
     let mut cursor = msg_coll
         .find(doc! { "_id": { "$in": Bson::Array(msg_id_array) } })
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+        .session(&mut *session)
+        .await?;
 
     let mut messages = Vec::new();
-    while let Some(m) = cursor
-        .try_next()
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
-    {
+    while let Some(m) = cursor.next(&mut *session).await.transpose()? {
         if m.conversation_id != p.conversation_id {
-            return Err(actix_web::error::ErrorBadRequest(
-                "All messages must belong to the specified conversation"
+            return Err(AppError::bad_request(
+                "conversation_mismatch",
+                "All messages must belong to the specified conversation",
             ));
         }
         messages.push(m);
     }
 
-    if messages.is_empty() {
-        return Err(actix_web::error::ErrorBadRequest("No valid messages found"));
+    let unknown: Vec<String> = p.message_ids.iter()
+        .filter(|id| !messages.iter().any(|m| m.id == **id))
+        .map(|id| id.to_string())
+        .collect();
+    if !unknown.is_empty() {
+        return Err(AppError::invalid(
+            "message_ids",
+            "unknown_messages",
+            format!("Unknown message ids: {}", unknown.join(", ")),
+        ));
     }
 
-    let from_date = messages.iter().map(|m| m.sent_at).min().unwrap();
-    let to_date = messages.iter().map(|m| m.sent_at).max().unwrap();
+    let from_date = messages.iter().map(|m| m.sent_at).min();
+    let to_date = messages.iter().map(|m| m.sent_at).max();
+    let (Some(from_date), Some(to_date)) = (from_date, to_date) else {
+        return Err(AppError::bad_request("no_messages", "No valid messages found"));
+    };
 
     let new_summary = MessageSummary {
-        id: Uuid::new_v4(),
+        id: MessageSummaryId::generate(),
         conversation_id: p.conversation_id,
-        message_ids: p.message_ids,
-        summary: p.summary,
-        context: p.context,
+        message_ids: p.message_ids.clone(),
+        summary: p.summary.clone(),
+        context: p.context.clone(),
         created_at: BsonDateTime::now(),
         from_date,
         to_date,
@@ -69,18 +116,20 @@ pub async fn create_message_summary(
 
     summary_coll
         .insert_one(&new_summary)
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+        .session(&mut *session)
+        .await?;
 
-    Ok(HttpResponse::Ok().json(new_summary))
+    Ok(new_summary)
 }
 
 #[get("/conversations/{id}/summaries")]
 pub async fn get_conversation_summaries(
     db: web::Data<Database>,
-    path: web::Path<Uuid>,
-) -> actix_web::Result<impl Responder> {
+    principal: Option<web::ReqData<Principal>>,
+    path: web::Path<ConversationId>,
+) -> AppResult<impl Responder> {
     let conv_id = path.into_inner();
+    auth::authorize_conversation(&db, principal.map(web::ReqData::into_inner), conv_id).await?;
     let summary_coll = db.collection::<MessageSummary>("message_summaries");
 
     let conv_id_str = conv_id.to_string();
@@ -92,14 +141,12 @@ pub async fn get_conversation_summaries(
     let mut cursor = summary_coll
         .find(doc! { "conversation_id": &conv_id_str })
         .with_options(options)
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+        .await?;
 
     let mut summaries = Vec::new();
     while let Some(s) = cursor
         .try_next()
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
+        .await?
     {
         summaries.push(s);
     }
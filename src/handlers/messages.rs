@@ -1,99 +1,1038 @@
-use actix_web::{get, post, put, web, HttpResponse, Responder};
-use bson::{doc, DateTime as BsonDateTime};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use actix_web::{get, post, put, web, HttpRequest, HttpResponse, Responder};
+use bson::{doc, Bson, DateTime as BsonDateTime, Document};
 use chrono::Utc;
 use futures::TryStreamExt;
 use mongodb::{
+    error::{ErrorKind, IndexedWriteError},
     options::{FindOptions, ReturnDocument},
+    ClientSession,
     Database,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
+use validator::Validate;
 
-use crate::models::{Conversation, Participant, Message};
+use super::{
+    address_filter, find_or_create_conversation, record_created_jobs, reply_jobs_for,
+    CreateConversationPayload,
+};
+use crate::auth::{self, Principal};
+use crate::error::{AppError, AppResult, Problem};
+use crate::events::{Event, EventBus, EventKind};
+use crate::ids::{ConversationId, MessageId, ParticipantId};
+use crate::indexes::{is_duplicate_key, DUPLICATE_KEY};
+use crate::models::{AddressKind, AgentJob, ConvRole, Conversation, IdempotencyKey, Participant, Message};
+use crate::transactions;
+use crate::validation::{
+    self, MAX_ADDRESS_CHARS, MAX_CONTENT_CHARS, MAX_EXTERNAL_ID_CHARS, MAX_TEXT_CHARS, MAX_TOPIC_CHARS,
+};
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Validate)]
 pub struct CreateMessagePayload {
-    pub conversation_id: Uuid,
-    pub sender_id: Uuid,
+    pub conversation_id: ConversationId,
+    pub sender_id: Option<ParticipantId>,
+    /// Resolves the sender by one of their addresses when `sender_id` is
+    /// absent; the address kind is derived from the channel.
+    #[validate(length(min = 1, max = MAX_ADDRESS_CHARS))]
+    pub sender_address: Option<String>,
+    #[validate(custom(function = validation::channel))]
+    pub channel: String,
+    #[validate(length(min = 1, max = MAX_EXTERNAL_ID_CHARS))]
+    pub external_id: Option<String>,
+    #[validate(custom(function = validation::sent_at))]
+    pub sent_at: chrono::DateTime<Utc>,
+    #[validate(custom(function = validation::not_blank), length(max = MAX_CONTENT_CHARS))]
+    pub content: String,
+    #[validate(length(max = MAX_TEXT_CHARS))]
+    pub summary: Option<String>,
+    #[validate(length(max = MAX_TEXT_CHARS))]
+    pub context: Option<String>,
+}
+
+const MAX_BATCH_MESSAGES: usize = 500;
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// A message as returned by the create endpoints.
+#[derive(Serialize)]
+pub struct StoredMessage {
+    #[serde(flatten)]
+    pub message: Message,
+    /// Set when the message had been stored before; nothing was written.
+    pub duplicate: bool,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct BatchMessageItem {
+    pub conversation_id: Option<ConversationId>,
+    /// Used when `conversation_id` is absent; the conversation is created if
+    /// no conversation has this external id yet.
+    pub conversation_external_id: Option<Uuid>,
+    /// Topic for conversations created by the batch.
+    #[validate(length(max = MAX_TOPIC_CHARS))]
+    pub conversation_topic: Option<String>,
+    pub sender_id: Option<ParticipantId>,
+    #[validate(length(min = 1, max = MAX_ADDRESS_CHARS))]
+    pub sender_address: Option<String>,
+    #[validate(custom(function = validation::channel))]
     pub channel: String,
+    #[validate(length(min = 1, max = MAX_EXTERNAL_ID_CHARS))]
     pub external_id: Option<String>,
+    #[validate(custom(function = validation::sent_at))]
     pub sent_at: chrono::DateTime<Utc>,
+    #[validate(custom(function = validation::not_blank), length(max = MAX_CONTENT_CHARS))]
     pub content: String,
+    #[validate(length(max = MAX_TEXT_CHARS))]
     pub summary: Option<String>,
+    #[validate(length(max = MAX_TEXT_CHARS))]
     pub context: Option<String>,
 }
 
 #[derive(Deserialize)]
+pub struct BatchMessagesPayload {
+    pub messages: Vec<BatchMessageItem>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BatchItemResult {
+    Created {
+        index: usize,
+        message_id: MessageId,
+        conversation_id: ConversationId,
+    },
+    /// The message was stored before, `message_id` refers to that copy.
+    Duplicate {
+        index: usize,
+        message_id: MessageId,
+        conversation_id: ConversationId,
+    },
+    Error {
+        index: usize,
+        error: Problem,
+    },
+}
+
+impl BatchItemResult {
+    fn error(index: usize, err: AppError) -> Self {
+        BatchItemResult::Error { index, error: err.problem() }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BatchMessagesResponse {
+    pub created: usize,
+    pub duplicates: usize,
+    pub failed: usize,
+    /// One entry per submitted message, in submission order.
+    pub results: Vec<BatchItemResult>,
+}
+
+#[derive(Deserialize, Validate)]
 pub struct UpdateMessageMetadataPayload {
+    #[validate(length(max = MAX_TEXT_CHARS))]
     pub summary: Option<String>,
+    #[validate(length(max = MAX_TEXT_CHARS))]
     pub context: Option<String>,
 }
 
+/// Pipeline update bumping the activity counters of a conversation for newly
+/// stored messages. Running it as a single update keeps the counters and the
+/// last-message pointer consistent under concurrent inserts, and messages
+/// arriving out of order never move `last_message_at` backwards.
+pub(crate) fn activity_update(msgs: &[Message]) -> Vec<Document> {
+    let mut per_channel: BTreeMap<&str, i64> = BTreeMap::new();
+    for msg in msgs {
+        *per_channel.entry(msg.channel.as_str()).or_default() += 1;
+    }
+
+    let mut channel_counts = doc! { "$ifNull": ["$channel_counts", {}] };
+    for (channel, count) in per_channel {
+        channel_counts = doc! {
+            "$setField": {
+                "field": { "$literal": channel },
+                "input": channel_counts,
+                "value": {
+                    "$add": [
+                        { "$ifNull": [{ "$getField": { "field": { "$literal": channel }, "input": "$channel_counts" } }, 0_i64] },
+                        count
+                    ]
+                }
+            }
+        };
+    }
+
+    let mut set_doc = doc! {
+        "message_count": { "$add": [{ "$ifNull": ["$message_count", 0_i64] }, msgs.len() as i64] },
+        "channel_counts": channel_counts,
+    };
+    if let Some(latest) = msgs.iter().max_by_key(|m| m.sent_at) {
+        set_doc.insert("last_message_id", doc! {
+            "$cond": [
+                { "$gte": [latest.sent_at, { "$ifNull": ["$last_message_at", BsonDateTime::MIN] }] },
+                latest.id.to_string(),
+                "$last_message_id"
+            ]
+        });
+        set_doc.insert("last_message_at", doc! { "$max": ["$last_message_at", latest.sent_at] });
+    }
+
+    vec![doc! { "$set": set_doc }]
+}
+
+/// Matches an already stored copy of a message: by channel and external id
+/// when the source system provides one, otherwise by idempotency key.
+fn dedupe_filter(channel: &str, external_id: Option<&str>, idempotency: Option<&IdempotencyKey>) -> Option<Document> {
+    let mut conditions = Vec::new();
+    if let Some(external_id) = external_id {
+        conditions.push(doc! { "channel": channel, "external_id": external_id });
+    }
+    if let Some(idempotency) = idempotency {
+        conditions.push(doc! { "idempotency.scope": &idempotency.scope, "idempotency.key": &idempotency.key });
+    }
+    match conditions.len() {
+        0 => None,
+        1 => conditions.pop(),
+        _ => Some(doc! { "$or": conditions }),
+    }
+}
+
+/// Reads the `Idempotency-Key` header. Keys are scoped to the caller, so two
+/// callers never collide, and bound to a hash of the payload.
+fn idempotency_key(
+    req: &HttpRequest,
+    principal: Option<Principal>,
+    p: &CreateMessagePayload,
+) -> AppResult<Option<IdempotencyKey>> {
+    let Some(value) = req.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+    let key = value.to_str()
+        .map_err(|_| AppError::bad_request("invalid_idempotency_key", "Idempotency-Key must be visible ASCII"))?
+        .trim();
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+        return Err(AppError::bad_request(
+            "invalid_idempotency_key",
+            format!("Idempotency-Key must be between 1 and {MAX_IDEMPOTENCY_KEY_LEN} characters"),
+        ));
+    }
+
+    let scope = match principal {
+        Some(Principal::ApiKey(id)) => format!("api_key:{id}"),
+        Some(Principal::Participant(id)) => format!("participant:{id}"),
+        // Authentication is disabled, everyone is the same caller.
+        None => "anonymous".to_string(),
+    };
+    let body = serde_json::to_vec(p).map_err(|e| AppError::Internal(e.to_string()))?;
+
+    Ok(Some(IdempotencyKey {
+        scope,
+        key: key.to_string(),
+        request_hash: hex::encode(Sha256::digest(body)),
+    }))
+}
+
+/// Answers a retry with the stored message, unless its `Idempotency-Key`
+/// was first used for a different request or it belongs to another sender
+/// than the participant `caller`, who must not see it.
+fn replayed(
+    message: Message,
+    idempotency: Option<&IdempotencyKey>,
+    caller: Option<ParticipantId>,
+) -> AppResult<StoredMessage> {
+    if caller.is_some_and(|caller| message.sender_id != caller) {
+        return Err(AppError::conflict("duplicate_message", "A message with this external id already exists"));
+    }
+    let reused = match (&message.idempotency, idempotency) {
+        (Some(stored), Some(sent)) => {
+            stored.scope == sent.scope && stored.key == sent.key && stored.request_hash != sent.request_hash
+        }
+        _ => false,
+    };
+    if reused {
+        return Err(AppError::invalid(
+            "Idempotency-Key",
+            "key_reused",
+            "Idempotency-Key was already used for a different request",
+        ));
+    }
+    Ok(StoredMessage { message, duplicate: true })
+}
+
+/// Creates a message. Retries are safe: a message with the same channel and
+/// external id, or the same `Idempotency-Key` header from the same caller, is
+/// returned as stored with `duplicate` set instead of being inserted again.
+/// Reusing a key for a different payload is rejected with 422.
 #[post("/messages")]
 pub async fn create_message(
     db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    principal: Option<web::ReqData<Principal>>,
+    req: HttpRequest,
     payload: web::Json<CreateMessagePayload>,
-) -> actix_web::Result<impl Responder> {
-    let p = payload.into_inner();
+) -> AppResult<impl Responder> {
+    let principal = principal.map(web::ReqData::into_inner);
+    let mut p = payload.into_inner();
+    let idempotency = idempotency_key(&req, principal, &p)?;
+
+    // Participants send as themselves, and only where they are already a
+    // member; joining a conversation by writing to it is left to services.
+    let caller = principal.and_then(Principal::participant);
+    if let Some(caller) = caller {
+        match p.sender_id {
+            None if p.sender_address.is_none() => p.sender_id = Some(caller),
+            Some(sender_id) if sender_id == caller => {}
+            _ => return Err(AppError::forbidden("not_yourself", "Participants can only send messages as themselves")),
+        }
+        auth::authorize_conversation(&db, principal, p.conversation_id).await?;
+    }
+
+    let stored = insert_message(&db, &events, p, idempotency, caller).await?;
+
+    Ok(HttpResponse::Ok().json(stored))
+}
+
+/// Stores a message together with its side effects: the sender joins the
+/// conversation, the activity counters move, AI members get reply jobs and
+/// subscribers are notified. None of that happens for duplicates, which are
+/// only returned to a participant `caller` who sent them. Shared by the HTTP
+/// and WebSocket entry points.
+pub(crate) async fn insert_message(
+    db: &Database,
+    events: &EventBus,
+    p: CreateMessagePayload,
+    idempotency: Option<IdempotencyKey>,
+    caller: Option<ParticipantId>,
+) -> AppResult<StoredMessage> {
+    p.validate()?;
+
     let conv_coll = db.collection::<Conversation>("conversations");
     let part_coll = db.collection::<Participant>("participants");
     let msg_coll = db.collection::<Message>("messages");
 
+    let dedupe = dedupe_filter(&p.channel, p.external_id.as_deref(), idempotency.as_ref());
+    if let Some(filter) = &dedupe {
+        let existing = msg_coll
+            .find_one(filter.clone())
+            .await?;
+        if let Some(message) = existing {
+            return replayed(message, idempotency.as_ref(), caller);
+        }
+    }
+
     let conv_id_str = p.conversation_id.to_string();
-    let sender_id_str = p.sender_id.to_string();
 
     let conv = conv_coll
         .find_one(doc! { "_id": &conv_id_str })
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
-        .ok_or_else(|| actix_web::error::ErrorNotFound("Conversation not found"))?;
+        .await?
+        .ok_or_else(|| AppError::not_found("conversation_not_found", "Conversation not found"))?;
+
+    let sender_filter = match (&p.sender_id, &p.sender_address) {
+        (Some(sender_id), _) => doc! { "_id": sender_id.to_string() },
+        (None, Some(address)) => address_filter(AddressKind::for_channel(&p.channel), address)?,
+        (None, None) => {
+            return Err(AppError::bad_request(
+                "sender_required",
+                "Either sender_id or sender_address is required",
+            ));
+        }
+    };
 
     let part = part_coll
-        .find_one(doc! { "_id": &sender_id_str })
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
-        .ok_or_else(|| actix_web::error::ErrorNotFound("Participant not found"))?;
+        .find_one(sender_filter)
+        .await?
+        .ok_or_else(|| AppError::not_found("participant_not_found", "Participant not found"))?;
 
-    let join_link = doc! {
-      "participant_id": &sender_id_str,
-      "joined_at": BsonDateTime::now()
-    };
+    let sender_id = part.id;
 
-    let _ = conv_coll
-        .update_one(
-            doc! {
-                "_id": &conv_id_str,
-                "participants.participant_id": { "$ne": &sender_id_str }
-            },
-            doc! { "$push": { "participants": join_link } },
-        )
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let membership = sender_membership(&conv, sender_id)?;
 
     let new_msg = Message {
-        id: Uuid::new_v4(),
+        id: MessageId::generate(),
         conversation_id: p.conversation_id,
-        sender_id: p.sender_id,
+        sender_id,
         channel: p.channel,
         external_id: p.external_id,
         sent_at: BsonDateTime::from_millis(p.sent_at.timestamp_millis()),
         content: p.content,
         summary: p.summary,
         context: p.context,
+        idempotency: idempotency.clone(),
+    };
+
+    let jobs = reply_jobs_for(db, &conv, &part, &new_msg)
+        .await?;
+
+    // Reset on every attempt; only the last one can have left writes behind.
+    let mut applied = AppliedWrites::default();
+    let now = BsonDateTime::now();
+    let result = transactions::run(db, async |session| {
+        applied = AppliedWrites::default();
+        write_message(db, events, session, &conv, &part, membership, now, &new_msg, &jobs, &mut applied).await?;
+        Ok(())
+    })
+    .await;
+
+    if let Err(e) = result {
+        if !transactions::supported() {
+            let mut session = db.client()
+                .start_session()
+                .await?;
+            undo_message(db, &mut session, membership, now, &new_msg, &applied).await;
+        }
+
+        // A concurrent retry may have won the race since the check above.
+        let Some(filter) = dedupe.filter(|_| matches!(&e, AppError::Database(err) if is_duplicate_key(err))) else {
+            return Err(e);
+        };
+        let message = msg_coll
+            .find_one(filter)
+            .await?
+            .ok_or(e)?;
+        return replayed(message, idempotency.as_ref(), caller);
+    }
+    for event in applied.events {
+        events.announce(event);
+    }
+
+    Ok(StoredMessage { message: new_msg, duplicate: false })
+}
+
+/// What storing a message does to its sender's conversation membership.
+#[derive(Clone, Copy)]
+enum SenderMembership {
+    Active,
+    Join,
+    /// The previous entry is kept so a failed write can restore it.
+    Rejoin { joined_at: BsonDateTime, left_at: BsonDateTime },
+}
+
+/// New senders join with their default role, departed ones rejoin if the
+/// conversation allows it.
+fn sender_membership(conv: &Conversation, sender_id: ParticipantId) -> AppResult<SenderMembership> {
+    match conv.membership(&sender_id) {
+        Some(cp) => match cp.left_at {
+            None => Ok(SenderMembership::Active),
+            Some(_) if !conv.allow_departed_senders => Err(AppError::forbidden(
+                "sender_departed",
+                "Sender has left this conversation",
+            )),
+            Some(left_at) => Ok(SenderMembership::Rejoin { joined_at: cp.joined_at, left_at }),
+        },
+        None => Ok(SenderMembership::Join),
+    }
+}
+
+/// Applies the membership change; returns whether anything was written.
+/// Both updates are guarded, so a concurrent join is not duplicated.
+async fn apply_sender_membership(
+    db: &Database,
+    session: &mut ClientSession,
+    conv_id: ConversationId,
+    part: &Participant,
+    membership: SenderMembership,
+    now: BsonDateTime,
+) -> mongodb::error::Result<bool> {
+    let conv_coll = db.collection::<Conversation>("conversations");
+    let conv_id_str = conv_id.to_string();
+
+    let result = match membership {
+        SenderMembership::Active => return Ok(false),
+        SenderMembership::Rejoin { .. } => conv_coll
+            .update_one(
+                doc! {
+                    "_id": &conv_id_str,
+                    "participants": { "$elemMatch": {
+                        "participant_id": &part.id,
+                        "left_at": { "$ne": null }
+                    } }
+                },
+                doc! { "$set": {
+                    "participants.$.left_at": null,
+                    "participants.$.joined_at": now
+                } },
+            )
+            .session(&mut *session)
+            .await?,
+        SenderMembership::Join => {
+            let role = bson::to_bson(&ConvRole::default_for(&part.participant_type))?;
+
+            let join_link = doc! {
+              "participant_id": &part.id,
+              "role": role,
+              "joined_at": now,
+              "left_at": null
+            };
+
+            conv_coll
+                .update_one(
+                    doc! {
+                        "_id": &conv_id_str,
+                        "participants.participant_id": { "$ne": &part.id }
+                    },
+                    doc! { "$push": { "participants": join_link } },
+                )
+                .session(&mut *session)
+                .await?
+        }
     };
 
-    msg_coll
-        .insert_one(&new_msg)
+    Ok(result.modified_count > 0)
+}
+
+/// Writes already applied by [`write_message`], for undoing them without a
+/// transaction.
+#[derive(Default)]
+struct AppliedWrites {
+    message: bool,
+    membership: bool,
+    jobs: bool,
+    events: Vec<Event>,
+}
+
+/// All writes for a new message, including its events. The activity update
+/// goes last so that it never needs undoing.
+#[allow(clippy::too_many_arguments)]
+async fn write_message(
+    db: &Database,
+    events: &EventBus,
+    session: &mut ClientSession,
+    conv: &Conversation,
+    part: &Participant,
+    membership: SenderMembership,
+    now: BsonDateTime,
+    msg: &Message,
+    jobs: &[AgentJob],
+    applied: &mut AppliedWrites,
+) -> mongodb::error::Result<()> {
+    db.collection::<Document>("messages")
+        .insert_one(msg.to_stored()?)
+        .session(&mut *session)
+        .await?;
+    applied.message = true;
+
+    applied.membership = apply_sender_membership(db, session, msg.conversation_id, part, membership, now).await?;
+
+    if !jobs.is_empty() {
+        db.collection::<AgentJob>("agent_jobs")
+            .insert_many(jobs)
+            .session(&mut *session)
+            .await?;
+        applied.jobs = true;
+    }
+
+    record_created_jobs(events, session, jobs, &mut applied.events).await?;
+    let event = events.record_for(session, EventKind::MessageCreated, conv, &[msg.sender_id], msg)
+        .await?;
+    applied.events.push(event);
+
+    db.collection::<Conversation>("conversations")
+        .update_one(
+            doc! { "_id": msg.conversation_id.to_string() },
+            activity_update(std::slice::from_ref(msg)),
+        )
+        .session(&mut *session)
+        .await?;
+
+    Ok(())
+}
+
+/// Standalone fallback: reverts the writes of a failed [`write_message`] in
+/// reverse order. Failures are logged, the original error is what the
+/// client gets.
+async fn undo_message(
+    db: &Database,
+    session: &mut ClientSession,
+    membership: SenderMembership,
+    now: BsonDateTime,
+    msg: &Message,
+    applied: &AppliedWrites,
+) {
+    remove_events(db, session, &applied.events).await;
+
+    if applied.jobs {
+        if let Err(e) = db.collection::<AgentJob>("agent_jobs")
+            .delete_many(doc! { "trigger_message_id": msg.id.to_string() })
+            .session(&mut *session)
+            .await
+        {
+            eprintln!("Failed to remove reply jobs of message {}: {e}", msg.id);
+        }
+    }
+
+    if applied.membership {
+        undo_sender_membership(db, session, msg.conversation_id, msg.sender_id, membership, now).await;
+    }
+
+    if applied.message {
+        if let Err(e) = db.collection::<Message>("messages")
+            .delete_one(doc! { "_id": msg.id.to_string() })
+            .session(&mut *session)
+            .await
+        {
+            eprintln!("Failed to remove message {}: {e}", msg.id);
+        }
+    }
+}
+
+/// Standalone fallback: removes events written before a later step failed.
+/// Readers skip the numbers of removed events once they have settled.
+async fn remove_events(db: &Database, session: &mut ClientSession, recorded: &[Event]) {
+    if recorded.is_empty() {
+        return;
+    }
+    let event_ids: Vec<i64> = recorded.iter().map(|e| e.id as i64).collect();
+    if let Err(e) = db.collection::<Event>("events")
+        .delete_many(doc! { "_id": { "$in": event_ids } })
+        .session(&mut *session)
         .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+    {
+        eprintln!("Failed to remove events {:?}: {e}", recorded.iter().map(|e| e.id).collect::<Vec<_>>());
+    }
+}
+
+/// Standalone fallback: reverts what [`apply_sender_membership`] wrote.
+async fn undo_sender_membership(
+    db: &Database,
+    session: &mut ClientSession,
+    conv_id: ConversationId,
+    sender_id: ParticipantId,
+    membership: SenderMembership,
+    now: BsonDateTime,
+) {
+    let conv_coll = db.collection::<Conversation>("conversations");
+    let conv_id_str = conv_id.to_string();
+    let sender_id_str = sender_id.to_string();
+
+    let result = match membership {
+        SenderMembership::Active => Ok(()),
+        SenderMembership::Join => conv_coll
+            .update_one(
+                doc! { "_id": &conv_id_str },
+                doc! { "$pull": { "participants": {
+                    "participant_id": &sender_id_str,
+                    "joined_at": now
+                } } },
+            )
+            .session(&mut *session)
+            .await
+            .map(|_| ()),
+        SenderMembership::Rejoin { joined_at, left_at } => conv_coll
+            .update_one(
+                doc! {
+                    "_id": &conv_id_str,
+                    "participants": { "$elemMatch": {
+                        "participant_id": &sender_id_str,
+                        "joined_at": now,
+                        "left_at": null
+                    } }
+                },
+                doc! { "$set": {
+                    "participants.$.joined_at": joined_at,
+                    "participants.$.left_at": left_at
+                } },
+            )
+            .session(&mut *session)
+            .await
+            .map(|_| ()),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to restore membership of {sender_id} in conversation {conv_id}: {e}");
+    }
+}
+
+/// Ingests many messages at once, e.g. when backfilling from another system.
+/// Lookups are shared across the batch, the messages are written with a
+/// single unordered `insert_many`, then each conversation's memberships,
+/// counters and events are written in one transaction. Invalid items, and
+/// the items of a conversation whose transaction fails, are reported
+/// without failing the others.
+/// Imported messages are history, so they do not queue AI replies.
+#[post("/messages/batch")]
+pub async fn create_messages_batch(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    payload: web::Json<BatchMessagesPayload>,
+) -> AppResult<impl Responder> {
+    let items = payload.into_inner().messages;
+    if items.is_empty() || items.len() > MAX_BATCH_MESSAGES {
+        return Err(AppError::invalid(
+            "messages",
+            "length",
+            format!("A batch holds between 1 and {MAX_BATCH_MESSAGES} messages"),
+        ));
+    }
+
+    let conv_coll = db.collection::<Conversation>("conversations");
+    let part_coll = db.collection::<Participant>("participants");
+    let msg_coll = db.collection::<Message>("messages");
+
+    // Conversations referenced by id, then those referenced by external id.
+    let conv_ids: HashSet<ConversationId> = items.iter().filter_map(|i| i.conversation_id).collect();
+    let mut convs: HashMap<ConversationId, Conversation> = HashMap::new();
+    if !conv_ids.is_empty() {
+        let ids: Vec<Bson> = conv_ids.iter().map(|id| Bson::String(id.to_string())).collect();
+        let mut cursor = conv_coll
+            .find(doc! { "_id": { "$in": ids } })
+            .await?;
+        while let Some(conv) = cursor
+            .try_next()
+            .await?
+        {
+            convs.insert(conv.id, conv);
+        }
+    }
+
+    // A conversation that cannot be created fails only the items using it.
+    let mut by_external_id: HashMap<Uuid, Result<ConversationId, Problem>> = HashMap::new();
+    // Invalid items are reported below and must not create conversations.
+    for item in items.iter().filter(|i| i.conversation_id.is_none() && i.validate().is_ok()) {
+        let Some(external_id) = item.conversation_external_id else { continue };
+        if by_external_id.contains_key(&external_id) {
+            continue;
+        }
+        let created = find_or_create_conversation(&db, &events, CreateConversationPayload {
+            external_id,
+            topic: item.conversation_topic.clone(),
+            allow_departed_senders: false,
+        }).await;
+        match created {
+            Ok(conv) => {
+                by_external_id.insert(external_id, Ok(conv.id));
+                convs.insert(conv.id, conv);
+            }
+            Err(e) => {
+                by_external_id.insert(external_id, Err(e.problem()));
+            }
+        }
+    }
+
+    // Senders referenced by id, then those referenced by address.
+    let sender_ids: HashSet<ParticipantId> = items.iter().filter_map(|i| i.sender_id).collect();
+    let mut senders: HashMap<ParticipantId, Participant> = HashMap::new();
+    if !sender_ids.is_empty() {
+        let ids: Vec<Bson> = sender_ids.iter().map(|id| Bson::String(id.to_string())).collect();
+        let mut cursor = part_coll
+            .find(doc! { "_id": { "$in": ids } })
+            .await?;
+        while let Some(part) = cursor
+            .try_next()
+            .await?
+        {
+            senders.insert(part.id, part);
+        }
+    }
+
+    let mut by_address: HashMap<(Option<AddressKind>, String), Option<ParticipantId>> = HashMap::new();
+    for item in items.iter().filter(|i| i.sender_id.is_none()) {
+        let Some(address) = &item.sender_address else { continue };
+        let kind = AddressKind::for_channel(&item.channel);
+        if by_address.contains_key(&(kind, address.clone())) {
+            continue;
+        }
+        // Invalid addresses are reported per item below.
+        let Ok(filter) = address_filter(kind, address) else { continue };
+        let part = part_coll
+            .find_one(filter)
+            .await?;
+        by_address.insert((kind, address.clone()), part.as_ref().map(|p| p.id));
+        if let Some(part) = part {
+            senders.insert(part.id, part);
+        }
+    }
+
+    // Messages the source system already delivered before.
+    let external_keys: Vec<Document> = items.iter()
+        .filter_map(|i| dedupe_filter(&i.channel, i.external_id.as_deref(), None))
+        .collect();
+    let mut existing: HashMap<(String, String), Message> = HashMap::new();
+    if !external_keys.is_empty() {
+        let mut cursor = msg_coll
+            .find(doc! { "$or": external_keys })
+            .await?;
+        while let Some(msg) = cursor
+            .try_next()
+            .await?
+        {
+            if let Some(external_id) = msg.external_id.clone() {
+                existing.insert((msg.channel.clone(), external_id), msg);
+            }
+        }
+    }
+
+    let mut results: Vec<Option<BatchItemResult>> = (0..items.len()).map(|_| None).collect();
+    let mut memberships: HashMap<(ConversationId, ParticipantId), Result<SenderMembership, Problem>> = HashMap::new();
+    let mut pending: Vec<(usize, Message)> = Vec::new();
+
+    for (index, item) in items.into_iter().enumerate() {
+        if let Err(e) = item.validate() {
+            results[index] = Some(BatchItemResult::error(index, e.into()));
+            continue;
+        }
 
-    Ok(HttpResponse::Ok().json(new_msg))
+        if let Some(external_id) = &item.external_id {
+            if let Some(msg) = existing.get(&(item.channel.clone(), external_id.clone())) {
+                results[index] = Some(BatchItemResult::Duplicate {
+                    index,
+                    message_id: msg.id,
+                    conversation_id: msg.conversation_id,
+                });
+                continue;
+            }
+        }
+
+        let conv_id = match (item.conversation_id, item.conversation_external_id) {
+            (Some(id), _) => Some(id),
+            (None, Some(external_id)) => match by_external_id.get(&external_id) {
+                Some(Err(error)) => {
+                    results[index] = Some(BatchItemResult::Error { index, error: error.clone() });
+                    continue;
+                }
+                Some(Ok(id)) => Some(*id),
+                None => None,
+            },
+            (None, None) => {
+                results[index] = Some(BatchItemResult::error(index, AppError::bad_request(
+                    "conversation_required",
+                    "Either conversation_id or conversation_external_id is required",
+                )));
+                continue;
+            }
+        };
+        let Some(conv) = conv_id.and_then(|id| convs.get(&id)) else {
+            results[index] = Some(BatchItemResult::error(
+                index,
+                AppError::not_found("conversation_not_found", "Conversation not found"),
+            ));
+            continue;
+        };
+
+        let part_id = match (&item.sender_id, &item.sender_address) {
+            (Some(sender_id), _) => Some(*sender_id),
+            (None, Some(address)) => {
+                let kind = AddressKind::for_channel(&item.channel);
+                if let Err(e) = address_filter(kind, address) {
+                    results[index] = Some(BatchItemResult::error(index, e));
+                    continue;
+                }
+                by_address.get(&(kind, address.clone())).cloned().flatten()
+            }
+            (None, None) => {
+                results[index] = Some(BatchItemResult::error(index, AppError::bad_request(
+                    "sender_required",
+                    "Either sender_id or sender_address is required",
+                )));
+                continue;
+            }
+        };
+        let Some(part) = part_id.and_then(|id| senders.get(&id)) else {
+            results[index] = Some(BatchItemResult::error(
+                index,
+                AppError::not_found("participant_not_found", "Participant not found"),
+            ));
+            continue;
+        };
+
+        let sender_id = part.id;
+
+        // Checked here, applied once per sender and conversation for the
+        // messages that end up stored.
+        let membership = memberships
+            .entry((conv.id, sender_id))
+            .or_insert_with(|| sender_membership(conv, sender_id).map_err(|e| e.problem()));
+        if let Err(error) = membership {
+            results[index] = Some(BatchItemResult::Error { index, error: error.clone() });
+            continue;
+        }
+
+        pending.push((index, Message {
+            id: MessageId::generate(),
+            conversation_id: conv.id,
+            sender_id,
+            channel: item.channel,
+            external_id: item.external_id,
+            sent_at: BsonDateTime::from_millis(item.sent_at.timestamp_millis()),
+            content: item.content,
+            summary: item.summary,
+            context: item.context,
+            idempotency: None,
+        }));
+    }
+
+    // Unordered, so one rejected document does not stop the rest.
+    let mut rejected: HashMap<usize, IndexedWriteError> = HashMap::new();
+    if !pending.is_empty() {
+        if let Err(e) = msg_coll
+            .insert_many(pending.iter().map(|(_, msg)| msg))
+            .ordered(false)
+            .await
+        {
+            match *e.kind {
+                ErrorKind::InsertMany(ref failure) if failure.write_errors.is_some() => {
+                    for write_error in failure.write_errors.iter().flatten() {
+                        rejected.insert(write_error.index, write_error.clone());
+                    }
+                }
+                _ => return Err(e.into()),
+            }
+        }
+    }
+
+    let mut inserted: HashMap<ConversationId, Vec<(usize, Message)>> = HashMap::new();
+    for (position, (index, msg)) in pending.into_iter().enumerate() {
+        results[index] = Some(match rejected.remove(&position) {
+            // Repeated within the batch or stored concurrently.
+            Some(write_error) if write_error.code == DUPLICATE_KEY => {
+                let filter = dedupe_filter(&msg.channel, msg.external_id.as_deref(), None);
+                let stored = match filter {
+                    Some(filter) => msg_coll
+                        .find_one(filter)
+                        .await?,
+                    None => None,
+                };
+                match stored {
+                    Some(stored) => BatchItemResult::Duplicate {
+                        index,
+                        message_id: stored.id,
+                        conversation_id: stored.conversation_id,
+                    },
+                    None => BatchItemResult::error(
+                        index,
+                        AppError::conflict("duplicate_message", "Message conflicts with a stored message"),
+                    ),
+                }
+            }
+            Some(write_error) => BatchItemResult::error(
+                index,
+                AppError::Internal(format!("failed to insert message: {}", write_error.message)),
+            ),
+            None => {
+                let result = BatchItemResult::Created {
+                    index,
+                    message_id: msg.id,
+                    conversation_id: msg.conversation_id,
+                };
+                inserted.entry(msg.conversation_id).or_default().push((index, msg));
+                result
+            }
+        });
+    }
+
+    // A conversation whose side effects cannot be written fails only its
+    // own items. Their messages are removed again, so a retry stores them
+    // in full instead of finding incomplete duplicates.
+    for (conv_id, stored) in &inserted {
+        let msgs: Vec<Message> = stored.iter().map(|(_, msg)| msg.clone()).collect();
+        let settled = match convs.get(conv_id) {
+            Some(conv) => settle_batch(&db, &events, conv, &msgs, &memberships, &senders).await,
+            None => Err(AppError::not_found("conversation_not_found", "Conversation not found")),
+        };
+        match settled {
+            Ok(recorded) => {
+                for event in recorded {
+                    events.announce(event);
+                }
+            }
+            Err(e) => {
+                let msg_ids: Vec<String> = msgs.iter().map(|m| m.id.to_string()).collect();
+                if let Err(e) = msg_coll
+                    .delete_many(doc! { "_id": { "$in": msg_ids } })
+                    .await
+                {
+                    eprintln!("Failed to remove the batch messages of conversation {conv_id}: {e}");
+                }
+                let error = e.problem();
+                for (index, _) in stored {
+                    results[*index] = Some(BatchItemResult::Error { index: *index, error: error.clone() });
+                }
+            }
+        }
+    }
+
+    let results: Vec<BatchItemResult> = results.into_iter().flatten().collect();
+    let created = results.iter()
+        .filter(|r| matches!(r, BatchItemResult::Created { .. }))
+        .count();
+    let duplicates = results.iter()
+        .filter(|r| matches!(r, BatchItemResult::Duplicate { .. }))
+        .count();
+
+    Ok(HttpResponse::Ok().json(BatchMessagesResponse {
+        created,
+        duplicates,
+        failed: results.len() - created - duplicates,
+        results,
+    }))
+}
+
+/// The side effects of the messages a batch stored in one conversation, in
+/// one transaction: their senders join, the activity counters move and the
+/// events are recorded. Returns the events to announce.
+async fn settle_batch(
+    db: &Database,
+    events: &EventBus,
+    conv: &Conversation,
+    msgs: &[Message],
+    memberships: &HashMap<(ConversationId, ParticipantId), Result<SenderMembership, Problem>>,
+    senders: &HashMap<ParticipantId, Participant>,
+) -> AppResult<Vec<Event>> {
+    let now = BsonDateTime::now();
+    // Reset on every attempt; only the last one can have left writes behind.
+    let mut joined: Vec<(ParticipantId, SenderMembership)> = Vec::new();
+    let mut recorded: Vec<Event> = Vec::new();
+
+    let result = transactions::run(db, async |session| {
+        joined.clear();
+        recorded.clear();
+
+        let mut seen: HashSet<ParticipantId> = HashSet::new();
+        for msg in msgs {
+            if !seen.insert(msg.sender_id) {
+                continue;
+            }
+            let key = (conv.id, msg.sender_id);
+            let (Some(Ok(membership)), Some(part)) = (memberships.get(&key), senders.get(&msg.sender_id)) else {
+                continue;
+            };
+            if apply_sender_membership(db, session, conv.id, part, *membership, now).await? {
+                joined.push((msg.sender_id, *membership));
+            }
+        }
+
+        for msg in msgs {
+            recorded.push(events.record_for(session, EventKind::MessageCreated, conv, &[msg.sender_id], msg).await?);
+        }
+
+        db.collection::<Conversation>("conversations")
+            .update_one(doc! { "_id": conv.id.to_string() }, activity_update(msgs))
+            .session(&mut *session)
+            .await?;
+        Ok(())
+    })
+    .await;
+
+    if let Err(e) = result {
+        if !transactions::supported() {
+            let mut session = db.client()
+                .start_session()
+                .await?;
+            remove_events(db, &mut session, &recorded).await;
+            for (sender_id, membership) in &joined {
+                undo_sender_membership(db, &mut session, conv.id, *sender_id, *membership, now).await;
+            }
+        }
+        return Err(e);
+    }
+    Ok(recorded)
 }
 
 #[get("/messages")]
 pub async fn get_all_messages(
     db: web::Data<Database>,
-) -> actix_web::Result<impl Responder> {
+) -> AppResult<impl Responder> {
     let msg_coll = db.collection::<Message>("messages");
 
     let options = FindOptions::builder()
@@ -103,14 +1042,12 @@ pub async fn get_all_messages(
     let mut cursor = msg_coll
         .find(doc! {})
         .with_options(options)
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+        .await?;
 
     let mut messages = Vec::new();
     while let Some(m) = cursor
         .try_next()
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
+        .await?
     {
         messages.push(m);
     }
@@ -121,8 +1058,9 @@ pub async fn get_all_messages(
 #[get("/messages/{id}")]
 pub async fn get_message(
     db: web::Data<Database>,
-    path: web::Path<Uuid>,
-) -> actix_web::Result<impl Responder> {
+    principal: Option<web::ReqData<Principal>>,
+    path: web::Path<MessageId>,
+) -> AppResult<impl Responder> {
     let msg_id = path.into_inner();
     let msg_coll = db.collection::<Message>("messages");
 
@@ -130,9 +1068,10 @@ pub async fn get_message(
 
     let msg = msg_coll
         .find_one(doc! { "_id": &msg_id_str })
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
-        .ok_or_else(|| actix_web::error::ErrorNotFound("Message not found"))?;
+        .await?
+        .ok_or_else(|| AppError::not_found("message_not_found", "Message not found"))?;
+
+    auth::authorize_conversation(&db, principal.map(web::ReqData::into_inner), msg.conversation_id).await?;
 
     Ok(HttpResponse::Ok().json(msg))
 }
@@ -140,11 +1079,13 @@ pub async fn get_message(
 #[put("/messages/{id}/metadata")]
 pub async fn update_message_metadata(
     db: web::Data<Database>,
-    path: web::Path<Uuid>,
+    events: web::Data<EventBus>,
+    path: web::Path<MessageId>,
     payload: web::Json<UpdateMessageMetadataPayload>,
-) -> actix_web::Result<impl Responder> {
+) -> AppResult<impl Responder> {
     let msg_id = path.into_inner();
     let p = payload.into_inner();
+    p.validate()?;
     let msg_coll = db.collection::<Message>("messages");
 
     let msg_id_str = msg_id.to_string();
@@ -157,15 +1098,81 @@ pub async fn update_message_metadata(
         update_doc.insert("context", context);
     }
 
-    let msg = msg_coll
-        .find_one_and_update(
-            doc! { "_id": &msg_id_str },
-            doc! { "$set": update_doc },
-        )
-        .return_document(ReturnDocument::After)
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
-        .ok_or_else(|| actix_web::error::ErrorNotFound("Message not found"))?;
+    let (msg, event) = transactions::run(&db, async |session| {
+        let msg = msg_coll
+            .find_one_and_update(
+                doc! { "_id": &msg_id_str },
+                doc! { "$set": update_doc.clone() },
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| AppError::not_found("message_not_found", "Message not found"))?;
+
+        let conv = db.collection::<Conversation>("conversations")
+            .find_one(doc! { "_id": msg.conversation_id.to_string() })
+            .session(&mut *session)
+            .await?;
+        let event = match &conv {
+            Some(conv) => events.record_for(session, EventKind::MessageUpdated, conv, &[], &msg).await,
+            None => events.record(session, EventKind::MessageUpdated, Some(msg.conversation_id), vec![], &msg).await,
+        }?;
+        Ok((msg, event))
+    })
+    .await?;
+    events.announce(event);
 
     Ok(HttpResponse::Ok().json(msg))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(scope: &str, request_hash: &str) -> IdempotencyKey {
+        IdempotencyKey { scope: scope.to_string(), key: "retry-1".to_string(), request_hash: request_hash.to_string() }
+    }
+
+    fn stored(idempotency: Option<IdempotencyKey>) -> Message {
+        Message {
+            id: MessageId::generate(),
+            conversation_id: ConversationId::generate(),
+            sender_id: ParticipantId::generate(),
+            channel: "sms".to_string(),
+            external_id: None,
+            sent_at: BsonDateTime::now(),
+            content: "hi".to_string(),
+            summary: None,
+            context: None,
+            idempotency,
+        }
+    }
+
+    #[test]
+    fn retries_with_the_same_payload_are_duplicates() {
+        let sent = key("api_key:a", "h1");
+        let replay = replayed(stored(Some(sent.clone())), Some(&sent), None).unwrap();
+        assert!(replay.duplicate);
+    }
+
+    #[test]
+    fn reusing_a_key_for_another_payload_is_rejected() {
+        let err = replayed(stored(Some(key("api_key:a", "h1"))), Some(&key("api_key:a", "h2")), None).err().unwrap();
+        assert!(matches!(err, AppError::Validation(_)));
+    }
+
+    #[test]
+    fn external_id_matches_ignore_the_key() {
+        assert!(replayed(stored(None), Some(&key("api_key:a", "h1")), None).is_ok());
+        assert!(replayed(stored(Some(key("api_key:b", "h1"))), None, None).is_ok());
+    }
+
+    #[test]
+    fn participants_only_get_back_their_own_messages() {
+        let message = stored(None);
+        let sender = message.sender_id;
+        assert!(replayed(message.clone(), None, Some(sender)).is_ok());
+        let err = replayed(message, None, Some(ParticipantId::generate())).err().unwrap();
+        assert!(matches!(err, AppError::Conflict { .. }));
+    }
+}
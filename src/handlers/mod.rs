@@ -1,9 +1,27 @@
+mod api_keys;
 mod participants;
+mod participant_merges;
+mod ai_configs;
 mod conversations;
+mod conversation_participants;
 mod messages;
 mod message_summaries;
+mod agent_jobs;
+mod event_streams;
+mod health;
+mod webhooks;
+mod websocket;
 
+pub use api_keys::*;
 pub use participants::*;
+pub use participant_merges::*;
+pub use ai_configs::*;
 pub use conversations::*;
+pub use conversation_participants::*;
 pub use messages::*;
-pub use message_summaries::*;
\ No newline at end of file
+pub use message_summaries::*;
+pub use agent_jobs::*;
+pub use event_streams::*;
+pub use health::*;
+pub use webhooks::*;
+pub use websocket::*;
\ No newline at end of file
@@ -0,0 +1,458 @@
+use actix_web::{get, post, web, HttpResponse, Responder};
+use bson::{doc, Bson, DateTime as BsonDateTime, Document};
+use futures::TryStreamExt;
+use mongodb::{
+    options::{FindOptions, ReturnDocument},
+    ClientSession,
+    Database,
+};
+use serde::Deserialize;
+
+use crate::error::{AppError, AppResult};
+use crate::events::{Event, EventBus, EventKind};
+use crate::ids::{ParticipantId, ParticipantMergeId};
+use crate::models::{
+    AgentJob, AiConfigVersion, ConvParticipant, ConvRole, Conversation, Message, Participant, ParticipantAddress,
+    ParticipantMerge, ParticipantRedirect,
+};
+use crate::transactions;
+
+#[derive(Deserialize)]
+pub struct MergeParticipantPayload {
+    pub source_id: ParticipantId,
+}
+
+/// Folds the participant `source_id` into the participant in the path, all
+/// in one transaction. A source with an AI config, config history or agent
+/// jobs is refused: those belong to the AI participant itself and have no
+/// meaning under another id.
+#[post("/participants/{id}/merge")]
+pub async fn merge_participant(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    path: web::Path<ParticipantId>,
+    payload: web::Json<MergeParticipantPayload>,
+) -> AppResult<impl Responder> {
+    let target_id = path.into_inner();
+    let source_id = payload.into_inner().source_id;
+
+    if source_id == target_id {
+        return Err(AppError::bad_request("self_merge", "Cannot merge a participant into itself"));
+    }
+
+    let mut applied = AppliedMerge::default();
+    let result = transactions::run(&db, async |session| {
+        // Reset on every attempt; only the last one can have left writes behind.
+        applied = AppliedMerge::default();
+        write_merge(&db, &events, session, target_id, source_id, &mut applied).await
+    })
+    .await;
+
+    let (target, event) = match result {
+        Ok(merged) => merged,
+        Err(e) => {
+            if !transactions::supported() {
+                let mut session = db.client()
+                    .start_session()
+                    .await?;
+                undo_merge(&db, &mut session, target_id, source_id, &applied).await;
+            }
+            return Err(e);
+        }
+    };
+    events.announce(event);
+
+    Ok(HttpResponse::Ok().json(target))
+}
+
+/// Writes already applied by [`write_merge`], for undoing them without a
+/// transaction.
+#[derive(Default)]
+struct AppliedMerge {
+    /// The deleted source profile.
+    source: Option<Participant>,
+    redirect: bool,
+    /// Redirects that pointed at the source before moving to the target.
+    rehomed: Vec<Bson>,
+    /// Addresses the target did not have before.
+    addresses: Vec<ParticipantAddress>,
+    /// Messages whose sender was the source.
+    messages: Vec<Bson>,
+    /// Conversations as they were before their membership was rewritten.
+    conversations: Vec<Conversation>,
+    record: Option<ParticipantMergeId>,
+}
+
+/// All writes of a merge, including its event. The audit record and the
+/// event go last so that they never need undoing.
+async fn write_merge(
+    db: &Database,
+    events: &EventBus,
+    session: &mut ClientSession,
+    target_id: ParticipantId,
+    source_id: ParticipantId,
+    applied: &mut AppliedMerge,
+) -> AppResult<(Participant, Event)> {
+    let part_coll = db.collection::<Participant>("participants");
+    let conv_coll = db.collection::<Conversation>("conversations");
+    let msg_coll = db.collection::<Message>("messages");
+    let redirect_coll = db.collection::<ParticipantRedirect>("participant_redirects");
+
+    let target = part_coll
+        .find_one(doc! { "_id": &target_id })
+        .session(&mut *session)
+        .await?
+        .ok_or_else(|| AppError::not_found("participant_not_found", "Target participant not found"))?;
+
+    let source = part_coll
+        .find_one(doc! { "_id": &source_id })
+        .session(&mut *session)
+        .await?
+        .ok_or_else(|| AppError::not_found("participant_not_found", "Source participant not found"))?;
+
+    let versions = db.collection::<AiConfigVersion>("ai_config_versions")
+        .count_documents(doc! { "participant_id": &source_id })
+        .session(&mut *session)
+        .await?;
+    if source.ai_config.is_some() || versions > 0 {
+        return Err(AppError::conflict("source_has_ai_config", "Cannot merge away a participant with an AI config"));
+    }
+    let jobs = db.collection::<AgentJob>("agent_jobs")
+        .count_documents(doc! { "participant_id": &source_id })
+        .session(&mut *session)
+        .await?;
+    if jobs > 0 {
+        return Err(AppError::conflict("source_has_agent_jobs", "Cannot merge away a participant with agent jobs"));
+    }
+
+    // The source goes away before its addresses move over, so an address is
+    // never owned by two participants at once.
+    part_coll
+        .delete_one(doc! { "_id": &source_id })
+        .session(&mut *session)
+        .await?;
+    applied.source = Some(source.clone());
+
+    let now = BsonDateTime::now();
+    redirect_coll
+        .insert_one(&ParticipantRedirect {
+            id: source_id,
+            target_id,
+            merged_at: now,
+        })
+        .session(&mut *session)
+        .await?;
+    applied.redirect = true;
+
+    // Keep redirects one hop deep.
+    let rehomed = redirect_coll
+        .distinct("_id", doc! { "target_id": &source_id })
+        .session(&mut *session)
+        .await?;
+    redirect_coll
+        .update_many(
+            doc! { "target_id": &source_id },
+            doc! { "$set": { "target_id": &target_id } },
+        )
+        .session(&mut *session)
+        .await?;
+    applied.rehomed = rehomed;
+
+    let added: Vec<ParticipantAddress> = source.addresses.iter()
+        .filter(|address| !target.addresses.contains(address))
+        .cloned()
+        .collect();
+    let target = part_coll
+        .find_one_and_update(
+            doc! { "_id": &target_id },
+            doc! { "$addToSet": { "addresses": { "$each": bson::to_bson(&source.addresses)? } } },
+        )
+        .return_document(ReturnDocument::After)
+        .session(&mut *session)
+        .await?
+        .ok_or_else(|| AppError::not_found("participant_not_found", "Target participant not found"))?;
+    applied.addresses = added;
+
+    // Only the standalone fallback needs to know which messages moved.
+    let mut msg_ids = Vec::new();
+    if !transactions::supported() {
+        let mut cursor = db.collection::<Document>("messages")
+            .find(doc! { "sender_id": &source_id })
+            .projection(doc! { "_id": 1 })
+            .session(&mut *session)
+            .await?;
+        while let Some(msg) = cursor.next(&mut *session).await.transpose()? {
+            msg_ids.extend(msg.get("_id").cloned());
+        }
+    }
+    let msg_result = msg_coll
+        .update_many(
+            doc! { "sender_id": &source_id },
+            doc! { "$set": { "sender_id": &target_id } },
+        )
+        .session(&mut *session)
+        .await?;
+    applied.messages = msg_ids;
+
+    let mut memberships = Vec::new();
+    let mut cursor = conv_coll
+        .find(doc! { "participants.participant_id": &source_id })
+        .session(&mut *session)
+        .await?;
+    while let Some(conv) = cursor.next(&mut *session).await.transpose()? {
+        memberships.push(conv);
+    }
+
+    // Where both were members their entries are combined, elsewhere the
+    // source's entry is taken over by the target.
+    let mut conversations_updated = 0;
+    for conv in memberships {
+        let Some(merged) = conv.membership(&source_id) else {
+            continue;
+        };
+        let result = match conv.membership(&target_id) {
+            Some(kept) => {
+                let entry = bson::to_bson(&combine_memberships(kept, merged))?;
+                conv_coll
+                    .update_one(
+                        doc! { "_id": conv.id },
+                        vec![doc! { "$set": { "participants": { "$concatArrays": [
+                            { "$filter": {
+                                "input": "$participants",
+                                "cond": { "$not": [{ "$in": ["$$this.participant_id", [&source_id, &target_id]] }] }
+                            } },
+                            [{ "$literal": entry }]
+                        ] } } }],
+                    )
+                    .session(&mut *session)
+                    .await?
+            }
+            None => conv_coll
+                .update_one(
+                    doc! { "_id": conv.id },
+                    doc! { "$set": { "participants.$[entry].participant_id": &target_id } },
+                )
+                .array_filters(vec![doc! { "entry.participant_id": &source_id }])
+                .session(&mut *session)
+                .await?,
+        };
+        conversations_updated += result.modified_count;
+        applied.conversations.push(conv);
+    }
+
+    let merge = ParticipantMerge {
+        id: ParticipantMergeId::generate(),
+        target_id,
+        source_id,
+        source_snapshot: source,
+        messages_updated: msg_result.modified_count,
+        conversations_updated,
+        merged_at: now,
+    };
+    db.collection::<ParticipantMerge>("participant_merges")
+        .insert_one(&merge)
+        .session(&mut *session)
+        .await?;
+    applied.record = Some(merge.id);
+
+    let event = events
+        .record_for_participants(session, EventKind::ParticipantMerged, &[target_id, source_id], &merge)
+        .await?;
+    Ok((target, event))
+}
+
+/// Standalone fallback: reverts the writes of a failed [`write_merge`] in
+/// reverse order. Failures are logged, the original error is what the
+/// client gets.
+async fn undo_merge(
+    db: &Database,
+    session: &mut ClientSession,
+    target_id: ParticipantId,
+    source_id: ParticipantId,
+    applied: &AppliedMerge,
+) {
+    if let Some(merge_id) = applied.record {
+        if let Err(e) = db.collection::<ParticipantMerge>("participant_merges")
+            .delete_one(doc! { "_id": merge_id })
+            .session(&mut *session)
+            .await
+        {
+            eprintln!("Failed to remove merge record {merge_id}: {e}");
+        }
+    }
+
+    let conv_coll = db.collection::<Conversation>("conversations");
+    for conv in &applied.conversations {
+        let restored = match bson::to_bson(&conv.participants) {
+            Ok(participants) => conv_coll
+                .update_one(doc! { "_id": conv.id }, doc! { "$set": { "participants": participants } })
+                .session(&mut *session)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = restored {
+            eprintln!("Failed to restore the members of conversation {}: {e}", conv.id);
+        }
+    }
+
+    if !applied.messages.is_empty() {
+        if let Err(e) = db.collection::<Message>("messages")
+            .update_many(
+                doc! { "_id": { "$in": applied.messages.clone() } },
+                doc! { "$set": { "sender_id": &source_id } },
+            )
+            .session(&mut *session)
+            .await
+        {
+            eprintln!("Failed to restore the messages of participant {source_id}: {e}");
+        }
+    }
+
+    let part_coll = db.collection::<Participant>("participants");
+    if !applied.addresses.is_empty() {
+        let restored = match bson::to_bson(&applied.addresses) {
+            Ok(addresses) => part_coll
+                .update_one(doc! { "_id": &target_id }, doc! { "$pullAll": { "addresses": addresses } })
+                .session(&mut *session)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = restored {
+            eprintln!("Failed to remove the merged addresses from participant {target_id}: {e}");
+        }
+    }
+
+    let redirect_coll = db.collection::<ParticipantRedirect>("participant_redirects");
+    if !applied.rehomed.is_empty() {
+        if let Err(e) = redirect_coll
+            .update_many(
+                doc! { "_id": { "$in": applied.rehomed.clone() } },
+                doc! { "$set": { "target_id": &source_id } },
+            )
+            .session(&mut *session)
+            .await
+        {
+            eprintln!("Failed to restore the redirects to participant {source_id}: {e}");
+        }
+    }
+
+    if applied.redirect {
+        if let Err(e) = redirect_coll
+            .delete_one(doc! { "_id": &source_id })
+            .session(&mut *session)
+            .await
+        {
+            eprintln!("Failed to remove the redirect of participant {source_id}: {e}");
+        }
+    }
+
+    if let Some(source) = &applied.source {
+        if let Err(e) = part_coll
+            .insert_one(source)
+            .session(&mut *session)
+            .await
+        {
+            eprintln!("Failed to restore participant {source_id}: {e}");
+        }
+    }
+}
+
+/// One entry for a participant that was a member under both ids: active if
+/// either entry is, with the stronger role and the later read cursor.
+fn combine_memberships(kept: &ConvParticipant, merged: &ConvParticipant) -> ConvParticipant {
+    let joined_at = match (kept.left_at, merged.left_at) {
+        (None, Some(_)) => kept.joined_at,
+        (Some(_), None) => merged.joined_at,
+        _ => kept.joined_at.min(merged.joined_at),
+    };
+    let left_at = match (kept.left_at, merged.left_at) {
+        (Some(kept), Some(merged)) => Some(kept.max(merged)),
+        _ => None,
+    };
+    ConvParticipant {
+        participant_id: kept.participant_id,
+        role: if role_rank(merged.role) > role_rank(kept.role) { merged.role } else { kept.role },
+        joined_at,
+        left_at,
+        last_read_at: kept.last_read_at.max(merged.last_read_at),
+    }
+}
+
+fn role_rank(role: ConvRole) -> u8 {
+    match role {
+        ConvRole::Owner => 3,
+        ConvRole::Member => 2,
+        ConvRole::Bot => 1,
+        ConvRole::Observer => 0,
+    }
+}
+
+#[get("/participants/{id}/merges")]
+pub async fn get_participant_merges(
+    db: web::Data<Database>,
+    path: web::Path<ParticipantId>,
+) -> AppResult<impl Responder> {
+    let part_id = path.into_inner();
+    let merge_coll = db.collection::<ParticipantMerge>("participant_merges");
+
+    let options = FindOptions::builder()
+        .sort(doc! { "merged_at": -1 })
+        .build();
+
+    let mut cursor = merge_coll
+        .find(doc! { "$or": [{ "target_id": &part_id }, { "source_id": &part_id }] })
+        .with_options(options)
+        .await?;
+
+    let mut merges = Vec::new();
+    while let Some(m) = cursor
+        .try_next()
+        .await?
+    {
+        merges.push(m);
+    }
+
+    Ok(HttpResponse::Ok().json(merges))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(role: ConvRole, joined: i64, left: Option<i64>, read: Option<i64>) -> ConvParticipant {
+        ConvParticipant {
+            participant_id: ParticipantId::generate(),
+            role,
+            joined_at: BsonDateTime::from_millis(joined),
+            left_at: left.map(BsonDateTime::from_millis),
+            last_read_at: read.map(BsonDateTime::from_millis),
+        }
+    }
+
+    #[test]
+    fn active_source_keeps_the_merged_member_active() {
+        let kept = entry(ConvRole::Member, 10, Some(20), Some(15));
+        let merged = entry(ConvRole::Owner, 30, None, Some(40));
+        let combined = combine_memberships(&kept, &merged);
+        assert_eq!(combined.participant_id, kept.participant_id);
+        assert_eq!(combined.role, ConvRole::Owner);
+        assert_eq!(combined.joined_at, merged.joined_at);
+        assert_eq!(combined.left_at, None);
+        assert_eq!(combined.last_read_at, merged.last_read_at);
+    }
+
+    #[test]
+    fn departed_twice_stays_departed_with_the_later_departure() {
+        let kept = entry(ConvRole::Owner, 10, Some(50), None);
+        let merged = entry(ConvRole::Observer, 5, Some(20), Some(15));
+        let combined = combine_memberships(&kept, &merged);
+        assert_eq!(combined.role, ConvRole::Owner);
+        assert_eq!(combined.joined_at, BsonDateTime::from_millis(5));
+        assert_eq!(combined.left_at, Some(BsonDateTime::from_millis(50)));
+        assert_eq!(combined.last_read_at, Some(BsonDateTime::from_millis(15)));
+    }
+}
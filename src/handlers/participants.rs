@@ -1,62 +1,377 @@
-use actix_web::{get, post, web, HttpResponse, Responder};
-use bson::doc;
+use std::collections::{HashMap, HashSet};
+
+use actix_web::{get, patch, post, put, web, HttpResponse, Responder};
+use bson::{doc, Bson, DateTime as BsonDateTime, Document};
+use chrono::Utc;
 use futures::TryStreamExt;
 use mongodb::{
     options::ReturnDocument,
+    Collection,
     Database,
 };
-use serde::Deserialize;
-use uuid::Uuid;
+use serde::{Deserialize, Deserializer, Serialize};
+use validator::Validate;
 
-use crate::models::{Participant, ParticipantType};
+use super::ConversationSort;
+use crate::error::{AppError, AppResult};
+use crate::events::{EventBus, EventKind};
+use crate::indexes::is_duplicate_key;
+use crate::ids::{ConversationId, MessageId, ParticipantId};
+use crate::models::{AddressKind, Conversation, Message, Participant, ParticipantAddress, ParticipantRedirect, ParticipantType};
+use crate::normalize::{normalize_address, InvalidAddress};
+use crate::transactions;
+use crate::validation::{self, MAX_ADDRESS_CHARS, MAX_DESCRIPTION_CHARS, MAX_NAME_CHARS};
 
-#[derive(Deserialize)]
+const DEFAULT_INBOX_LIMIT: usize = 20;
+const MAX_INBOX_LIMIT: usize = 100;
+const PREVIEW_CHARS: usize = 140;
+
+#[derive(Deserialize, Validate)]
 pub struct CreateParticipantPayload {
-    pub address: String,
+    #[serde(default)]
+    pub addresses: Vec<ParticipantAddress>,
+    /// Legacy single address, its kind is inferred from the value.
+    #[validate(length(min = 1, max = MAX_ADDRESS_CHARS))]
+    pub address: Option<String>,
+    #[validate(custom(function = validation::not_blank), length(max = MAX_NAME_CHARS))]
     pub display_name: Option<String>,
     #[serde(rename = "type")]
     pub participant_type: ParticipantType,
+    #[validate(length(max = MAX_DESCRIPTION_CHARS))]
     pub description: Option<String>,
 }
 
+impl CreateParticipantPayload {
+    fn all_addresses(&self) -> Result<Vec<ParticipantAddress>, InvalidAddress> {
+        let mut all = self.addresses.clone();
+        if let Some(value) = &self.address {
+            all.push(ParticipantAddress { kind: AddressKind::infer(value), value: value.clone() });
+        }
+        for a in all.iter_mut() {
+            a.value = normalize_address(a.kind, &a.value)?;
+        }
+        let mut seen = HashSet::new();
+        all.retain(|a| seen.insert(a.clone()));
+        Ok(all)
+    }
+}
+
+/// Partial update: absent fields are left untouched, `null` clears them.
+#[derive(Deserialize, Validate)]
+pub struct UpdateParticipantPayload {
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[validate(custom(function = validation::not_blank), length(max = MAX_NAME_CHARS))]
+    pub display_name: Option<Option<String>>,
+    #[serde(rename = "type")]
+    pub participant_type: Option<ParticipantType>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[validate(length(max = MAX_DESCRIPTION_CHARS))]
+    pub description: Option<Option<String>>,
+}
+
+/// Maps a present field to `Some`, keeping `null` apart from a missing field.
+fn explicit_null<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+pub struct AddressLookupQuery {
+    pub kind: Option<AddressKind>,
+    pub value: String,
+}
+
+#[derive(Deserialize)]
+pub struct InboxQuery {
+    pub sort: Option<ConversationSort>,
+    pub offset: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct MarkReadPayload {
+    pub read_at: Option<chrono::DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct MessagePreview {
+    pub id: MessageId,
+    pub sender_id: ParticipantId,
+    pub channel: String,
+    pub sent_at: BsonDateTime,
+    pub content: String,
+}
+
+#[derive(Serialize)]
+pub struct InboxEntry {
+    pub conversation: Conversation,
+    pub last_message: Option<MessagePreview>,
+    pub unread_count: u64,
+}
+
+#[derive(Serialize)]
+pub struct InboxPage {
+    pub total: u64,
+    pub offset: u64,
+    pub limit: usize,
+    pub items: Vec<InboxEntry>,
+}
+
+/// Filter matching participants owning the given address, after normalizing
+/// it the same way stored addresses are. Without a kind the value is matched
+/// against addresses of every kind.
+pub(crate) fn address_filter(kind: Option<AddressKind>, value: &str) -> AppResult<Document> {
+    Ok(match kind {
+        Some(kind) => {
+            let value = normalize_address(kind, value)
+                .map_err(|e| AppError::bad_request("invalid_address", e.to_string()))?;
+            let kind = bson::to_bson(&kind)?;
+            doc! { "addresses": { "$elemMatch": { "kind": kind, "value": value } } }
+        }
+        None => {
+            let value = normalize_address(AddressKind::infer(value), value)
+                .unwrap_or_else(|_| value.trim().to_string());
+            doc! { "addresses.value": value }
+        }
+    })
+}
+
+/// The address checks read before writing, so a concurrent write can still
+/// claim an address first; the unique index then rejects this one.
+fn address_race(err: AppError) -> AppError {
+    match err {
+        AppError::Database(e) if is_duplicate_key(&e) => {
+            AppError::conflict("address_taken", "Address already belongs to another participant")
+        }
+        err => err,
+    }
+}
+
+/// Ids of the participants owning any of the given addresses.
+async fn address_owners(
+    part_coll: &Collection<Participant>,
+    address_filters: &[Document],
+) -> AppResult<Vec<ParticipantId>> {
+    let mut cursor = part_coll
+        .find(doc! { "$or": address_filters })
+        .await?;
+
+    let mut owner_ids = Vec::new();
+    while let Some(existing) = cursor
+        .try_next()
+        .await?
+    {
+        owner_ids.push(existing.id);
+    }
+
+    Ok(owner_ids)
+}
+
 #[post("/participants")]
 pub async fn create_participant(
     db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    payload: web::Json<CreateParticipantPayload>,
+) -> AppResult<impl Responder> {
+    let p = payload.into_inner();
+    p.validate()?;
+    let part_coll = db.collection::<Participant>("participants");
+
+    let addresses = p.all_addresses()
+        .map_err(|e| AppError::invalid("addresses", "invalid_address", e.to_string()))?;
+    if addresses.is_empty() {
+        return Err(AppError::invalid("addresses", "required", "At least one address is required"));
+    }
+
+    let address_filters = addresses.iter()
+        .map(|a| address_filter(Some(a.kind), &a.value))
+        .collect::<AppResult<Vec<_>>>()?;
+
+    if let Some(owner_id) = address_owners(&part_coll, &address_filters).await?.first() {
+        return Err(AppError::conflict("address_taken", format!(
+            "Address already belongs to participant {owner_id}"
+        )));
+    }
+
+    let part = Participant {
+        id: ParticipantId::generate(),
+        addresses,
+        display_name: p.display_name,
+        participant_type: p.participant_type,
+        description: p.description,
+        ai_config: None,
+    };
+
+    let event = transactions::run(&db, async |session| {
+        part_coll
+            .insert_one(&part)
+            .session(&mut *session)
+            .await?;
+
+        let event = events.record_for_participants(session, EventKind::ParticipantCreated, &[part.id], &part)
+            .await?;
+        Ok(event)
+    })
+    .await
+    .map_err(address_race)?;
+    events.announce(event);
+
+    Ok(HttpResponse::Created().json(part))
+}
+
+/// Creates the participant or adds the addresses to the one already owning
+/// them. Only fields present in the payload are written, so omitting a field
+/// never clears an existing profile.
+#[post("/participants/upsert")]
+pub async fn upsert_participant(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
     payload: web::Json<CreateParticipantPayload>,
-) -> actix_web::Result<impl Responder> {
+) -> AppResult<impl Responder> {
+    let p = payload.into_inner();
+    p.validate()?;
+    let part_coll = db.collection::<Participant>("participants");
+
+    let addresses = p.all_addresses()
+        .map_err(|e| AppError::invalid("addresses", "invalid_address", e.to_string()))?;
+    if addresses.is_empty() {
+        return Err(AppError::invalid("addresses", "required", "At least one address is required"));
+    }
+
+    let participant_type_bson = bson::to_bson(&p.participant_type)?;
+    let addresses_bson = bson::to_bson(&addresses)?;
+
+    let address_filters = addresses.iter()
+        .map(|a| address_filter(Some(a.kind), &a.value))
+        .collect::<AppResult<Vec<_>>>()?;
+
+    let owner_ids = address_owners(&part_coll, &address_filters).await?;
+    if owner_ids.len() > 1 {
+        return Err(AppError::conflict(
+            "address_owners_differ",
+            "Addresses belong to different participants"
+        ));
+    }
+
+    let filter = match owner_ids.first() {
+        Some(id) => doc! { "_id": id },
+        None => doc! { "$or": address_filters },
+    };
+
+    let mut set_doc = doc! { "type": participant_type_bson };
+    let mut set_on_insert = doc! { "_id": ParticipantId::generate() };
+    for (field, value) in [("display_name", &p.display_name), ("description", &p.description)] {
+        match value {
+            Some(v) => set_doc.insert(field, v),
+            None => set_on_insert.insert(field, Bson::Null),
+        };
+    }
+
+    let kind = if owner_ids.is_empty() {
+        EventKind::ParticipantCreated
+    } else {
+        EventKind::ParticipantUpdated
+    };
+
+    let (part, event) = transactions::run(&db, async |session| {
+        let part = part_coll
+            .find_one_and_update(
+                filter.clone(),
+                doc! {
+                  "$setOnInsert": set_on_insert.clone(),
+                  "$addToSet": {
+                    "addresses": { "$each": addresses_bson.clone() }
+                  },
+                  "$set": set_doc.clone()
+                },
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| AppError::Internal("upserted participant not returned".to_string()))?;
+
+        let event = events.record_for_participants(session, kind, &[part.id], &part)
+            .await?;
+        Ok((part, event))
+    })
+    .await
+    .map_err(address_race)?;
+    events.announce(event);
+
+    Ok(HttpResponse::Ok().json(part))
+}
+
+#[patch("/participants/{id}")]
+pub async fn update_participant(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    path: web::Path<ParticipantId>,
+    payload: web::Json<UpdateParticipantPayload>,
+) -> AppResult<impl Responder> {
+    let part_id = path.into_inner();
     let p = payload.into_inner();
+    p.validate()?;
     let part_coll = db.collection::<Participant>("participants");
 
-    let participant_type_bson = bson::to_bson(&p.participant_type)
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let mut update_doc = doc! {};
+    if let Some(display_name) = p.display_name {
+        update_doc.insert("display_name", display_name);
+    }
+    if let Some(description) = p.description {
+        update_doc.insert("description", description);
+    }
+    if let Some(participant_type) = p.participant_type {
+        let participant_type_bson = bson::to_bson(&participant_type)?;
+        update_doc.insert("type", participant_type_bson);
+    }
+
+    let part = if update_doc.is_empty() {
+        part_coll
+            .find_one(doc! { "_id": part_id })
+            .await?
+            .ok_or_else(|| AppError::not_found("participant_not_found", "Participant not found"))?
+    } else {
+        let (part, event) = transactions::run(&db, async |session| {
+            let part = part_coll
+                .find_one_and_update(
+                    doc! { "_id": part_id },
+                    doc! { "$set": update_doc.clone() },
+                )
+                .return_document(ReturnDocument::After)
+                .session(&mut *session)
+                .await?
+                .ok_or_else(|| AppError::not_found("participant_not_found", "Participant not found"))?;
+
+            let event = events.record_for_participants(session, EventKind::ParticipantUpdated, &[part.id], &part)
+                .await?;
+            Ok((part, event))
+        })
+        .await?;
+        events.announce(event);
+        part
+    };
+
+    Ok(HttpResponse::Ok().json(part))
+}
+
+#[get("/participants/lookup")]
+pub async fn lookup_participant(
+    db: web::Data<Database>,
+    query: web::Query<AddressLookupQuery>,
+) -> AppResult<impl Responder> {
+    let q = query.into_inner();
+    let part_coll = db.collection::<Participant>("participants");
+
+    let filter = address_filter(q.kind, &q.value)?;
 
     let part = part_coll
-        .find_one_and_update(
-            doc! { "address": &p.address },
-            doc! {
-              "$setOnInsert": {
-                "_id": Uuid::new_v4().to_string(),
-                "address": &p.address
-              },
-              "$set": {
-                "display_name": &p.display_name,
-                "type": participant_type_bson,
-                "description": &p.description
-              }
-            },
-        )
-        .upsert(true)
-        .return_document(ReturnDocument::After)
-        .await
-        .map_err(|e| {
-            eprintln!("MongoDB error in create_participant: {:?}", e);
-            eprintln!("Error details: {}", e);
-            actix_web::error::ErrorInternalServerError(format!("Database error: {}", e))
-        })?
-        .ok_or_else(|| {
-            eprintln!("Failed to get participant after upsert");
-            actix_web::error::ErrorInternalServerError("Failed to create or retrieve participant")
-        })?;
+        .find_one(filter)
+        .await?
+        .ok_or_else(|| AppError::not_found("participant_not_found", "Participant not found"))?;
 
     Ok(HttpResponse::Ok().json(part))
 }
@@ -64,19 +379,17 @@ pub async fn create_participant(
 #[get("/participants")]
 pub async fn get_all_participants(
     db: web::Data<Database>,
-) -> actix_web::Result<impl Responder> {
+) -> AppResult<impl Responder> {
     let part_coll = db.collection::<Participant>("participants");
 
     let mut cursor = part_coll
         .find(doc! {})
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+        .await?;
 
     let mut participants = Vec::new();
     while let Some(p) = cursor
         .try_next()
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
+        .await?
     {
         participants.push(p);
     }
@@ -87,17 +400,216 @@ pub async fn get_all_participants(
 #[get("/participants/{id}")]
 pub async fn get_participant(
     db: web::Data<Database>,
-    path: web::Path<String>,
-) -> actix_web::Result<impl Responder> {
+    path: web::Path<ParticipantId>,
+) -> AppResult<impl Responder> {
     let part_id = path.into_inner();
     let part_coll = db.collection::<Participant>("participants");
     println!("Start to process query {part_id}");
 
-    let part = part_coll
-        .find_one(doc! { "_id": &part_id })
-        .await
-        .map_err(actix_web::error::ErrorInternalServerError)?
-        .ok_or_else(|| actix_web::error::ErrorNotFound("Participant not found"))?;
+    let mut part = part_coll
+        .find_one(doc! { "_id": part_id })
+        .await?;
+
+    // Ids of participants merged into another one keep resolving to the target.
+    if part.is_none() {
+        let redirect = db.collection::<ParticipantRedirect>("participant_redirects")
+            .find_one(doc! { "_id": part_id })
+            .await?;
+        if let Some(redirect) = redirect {
+            part = part_coll
+                .find_one(doc! { "_id": redirect.target_id })
+                .await?;
+        }
+    }
+
+    let part = part.ok_or_else(|| AppError::not_found("participant_not_found", "Participant not found"))?;
 
     Ok(HttpResponse::Ok().json(part))
-}
\ No newline at end of file
+}
+
+#[get("/participants/{id}/conversations")]
+pub async fn get_participant_conversations(
+    db: web::Data<Database>,
+    path: web::Path<ParticipantId>,
+    query: web::Query<InboxQuery>,
+) -> AppResult<impl Responder> {
+    let part_id = path.into_inner();
+    let q = query.into_inner();
+    let part_coll = db.collection::<Participant>("participants");
+    let conv_coll = db.collection::<Conversation>("conversations");
+    let msg_coll = db.collection::<Message>("messages");
+
+    let offset = q.offset.unwrap_or(0);
+    let limit = q.limit.unwrap_or(DEFAULT_INBOX_LIMIT).clamp(1, MAX_INBOX_LIMIT);
+
+    part_coll
+        .find_one(doc! { "_id": part_id })
+        .await?
+        .ok_or_else(|| AppError::not_found("participant_not_found", "Participant not found"))?;
+
+    let filter = doc! {
+        "participants": { "$elemMatch": { "participant_id": part_id, "left_at": null } }
+    };
+
+    let total = conv_coll
+        .count_documents(filter.clone())
+        .await?;
+
+    let mut pipeline = vec![doc! { "$match": filter }];
+    pipeline.extend(q.sort.unwrap_or(ConversationSort::LastActivity).stages());
+    pipeline.push(doc! { "$skip": offset as i64 });
+    pipeline.push(doc! { "$limit": limit as i64 });
+
+    let convs: Vec<Conversation> = conv_coll
+        .aggregate(pipeline)
+        .with_type::<Conversation>()
+        .await?
+        .try_collect()
+        .await?;
+
+    // The page costs two more round trips whatever its size: the last
+    // messages the conversations point to, and the unread counts.
+    let last_ids: Vec<MessageId> = convs.iter().filter_map(|c| c.last_message_id).collect();
+    let mut last_messages: HashMap<MessageId, Message> = HashMap::new();
+    if !last_ids.is_empty() {
+        let mut cursor = msg_coll
+            .find(doc! { "_id": { "$in": bson::to_bson(&last_ids)? } })
+            .await?;
+        while let Some(m) = cursor
+            .try_next()
+            .await?
+        {
+            last_messages.insert(m.id, m);
+        }
+    }
+
+    let unread = unread_counts(&msg_coll, part_id, &convs).await?;
+
+    let items = convs.into_iter()
+        .map(|conv| InboxEntry {
+            last_message: conv.last_message_id
+                .and_then(|id| last_messages.remove(&id))
+                .map(|m| MessagePreview {
+                    id: m.id,
+                    sender_id: m.sender_id,
+                    channel: m.channel,
+                    sent_at: m.sent_at,
+                    content: m.content.chars().take(PREVIEW_CHARS).collect(),
+                }),
+            unread_count: unread.get(&conv.id).copied().unwrap_or(0),
+            conversation: conv,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(InboxPage { total, offset, limit, items }))
+}
+
+#[derive(Deserialize)]
+struct UnreadCount {
+    #[serde(rename = "_id")]
+    conversation_id: ConversationId,
+    count: u64,
+}
+
+/// Messages from others after the participant's read cursor, counted for all
+/// conversations in one aggregation.
+async fn unread_counts(
+    msg_coll: &Collection<Message>,
+    part_id: ParticipantId,
+    convs: &[Conversation],
+) -> AppResult<HashMap<ConversationId, u64>> {
+    let unread_filters: Vec<Document> = convs.iter()
+        .map(|conv| {
+            let mut filter = doc! { "conversation_id": conv.id, "sender_id": { "$ne": part_id } };
+            if let Some(read_at) = conv.membership(&part_id).and_then(|cp| cp.last_read_at) {
+                filter.insert("sent_at", doc! { "$gt": read_at });
+            }
+            filter
+        })
+        .collect();
+    if unread_filters.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let mut cursor = msg_coll
+        .aggregate(vec![
+            doc! { "$match": { "$or": unread_filters } },
+            doc! { "$group": { "_id": "$conversation_id", "count": { "$sum": 1_i64 } } },
+        ])
+        .with_type::<UnreadCount>()
+        .await?;
+
+    let mut counts = HashMap::new();
+    while let Some(unread) = cursor
+        .try_next()
+        .await?
+    {
+        counts.insert(unread.conversation_id, unread.count);
+    }
+    Ok(counts)
+}
+
+#[put("/participants/{id}/conversations/{conv_id}/read")]
+pub async fn mark_conversation_read(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    path: web::Path<(ParticipantId, ConversationId)>,
+    payload: web::Json<MarkReadPayload>,
+) -> AppResult<impl Responder> {
+    let (part_id, conv_id) = path.into_inner();
+    let p = payload.into_inner();
+
+    let conv = mark_read(&db, &events, part_id, conv_id, p.read_at).await?;
+
+    Ok(HttpResponse::Ok().json(conv))
+}
+
+/// Moves the read cursor of a member forward, `$max` keeps it from moving
+/// backwards on out-of-order updates. Other members are told about the new
+/// cursor so they can show read receipts.
+pub(crate) async fn mark_read(
+    db: &Database,
+    events: &EventBus,
+    part_id: ParticipantId,
+    conv_id: ConversationId,
+    read_at: Option<chrono::DateTime<Utc>>,
+) -> AppResult<Conversation> {
+    let conv_coll = db.collection::<Conversation>("conversations");
+
+    let read_at = read_at
+        .map(|t| BsonDateTime::from_millis(t.timestamp_millis()))
+        .unwrap_or_else(BsonDateTime::now);
+
+    let (conv, event) = transactions::run(db, async |session| {
+        let conv = conv_coll
+            .find_one_and_update(
+                doc! {
+                    "_id": conv_id,
+                    "participants.participant_id": part_id
+                },
+                doc! { "$max": { "participants.$.last_read_at": read_at } },
+            )
+            .return_document(ReturnDocument::After)
+            .session(&mut *session)
+            .await?
+            .ok_or_else(|| AppError::not_found("membership_not_found", "Conversation membership not found"))?;
+
+        let last_read_at = conv.participants.iter()
+            .find(|cp| cp.participant_id == part_id)
+            .and_then(|cp| cp.last_read_at);
+        let event = events
+            .record_for(
+                session,
+                EventKind::ConversationRead,
+                &conv,
+                &[],
+                &doc! { "participant_id": part_id, "last_read_at": last_read_at },
+            )
+            .await?;
+        Ok((conv, event))
+    })
+    .await?;
+    events.announce(event);
+
+    Ok(conv)
+}
@@ -0,0 +1,277 @@
+use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use bson::{doc, DateTime as BsonDateTime};
+use futures::TryStreamExt;
+use mongodb::{options::ReturnDocument, Database};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AppError, AppResult};
+use crate::events::EventKind;
+use crate::ids::{ConversationId, WebhookDeliveryId, WebhookId};
+use crate::models::{WebhookDelivery, WebhookDeliveryStatus, WebhookSubscription};
+use crate::webhooks::{check_destination, generate_secret};
+
+const MIN_SECRET_LEN: usize = 16;
+const DELIVERY_PAGE: i64 = 100;
+
+#[derive(Deserialize)]
+pub struct CreateWebhookPayload {
+    pub url: String,
+    #[serde(default)]
+    pub event_types: Vec<EventKind>,
+    #[serde(default)]
+    pub conversation_ids: Vec<ConversationId>,
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Generated when omitted.
+    pub secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct WebhookDeliveryQuery {
+    pub status: Option<WebhookDeliveryStatus>,
+}
+
+#[derive(Deserialize)]
+pub struct DeadLetterQuery {
+    pub subscription_id: Option<WebhookId>,
+}
+
+/// A subscription as listed; the secret is only returned on creation.
+#[derive(Serialize)]
+pub struct WebhookView {
+    #[serde(rename = "_id")]
+    pub id: WebhookId,
+    pub url: String,
+    pub event_types: Vec<EventKind>,
+    pub conversation_ids: Vec<ConversationId>,
+    pub channels: Vec<String>,
+    pub created_at: BsonDateTime,
+}
+
+impl From<WebhookSubscription> for WebhookView {
+    fn from(sub: WebhookSubscription) -> Self {
+        WebhookView {
+            id: sub.id,
+            url: sub.url,
+            event_types: sub.event_types,
+            conversation_ids: sub.conversation_ids,
+            channels: sub.channels,
+            created_at: sub.created_at,
+        }
+    }
+}
+
+#[post("/webhooks")]
+pub async fn create_webhook(
+    db: web::Data<Database>,
+    payload: web::Json<CreateWebhookPayload>,
+) -> AppResult<impl Responder> {
+    let p = payload.into_inner();
+    let sub_coll = db.collection::<WebhookSubscription>("webhooks");
+
+    let url = reqwest::Url::parse(p.url.trim())
+        .map_err(|e| AppError::invalid("url", "invalid_url", format!("Invalid url: {e}")))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(AppError::invalid("url", "invalid_scheme", "Webhook url must use http or https"));
+    }
+    check_destination(&url)
+        .await
+        .map_err(|e| AppError::invalid("url", "forbidden_destination", e))?;
+
+    let secret = match p.secret {
+        Some(secret) if secret.len() < MIN_SECRET_LEN => {
+            return Err(AppError::invalid(
+                "secret",
+                "too_short",
+                format!("Secret must be at least {MIN_SECRET_LEN} characters"),
+            ));
+        }
+        Some(secret) => secret,
+        None => generate_secret(),
+    };
+
+    let sub = WebhookSubscription {
+        id: WebhookId::generate(),
+        url: url.to_string(),
+        secret,
+        event_types: p.event_types,
+        conversation_ids: p.conversation_ids,
+        channels: p.channels.iter().map(|c| c.trim().to_ascii_lowercase()).collect(),
+        created_at: BsonDateTime::now(),
+    };
+
+    sub_coll
+        .insert_one(&sub)
+        .await?;
+
+    Ok(HttpResponse::Created().json(sub))
+}
+
+#[get("/webhooks")]
+pub async fn get_webhooks(
+    db: web::Data<Database>,
+) -> AppResult<impl Responder> {
+    let sub_coll = db.collection::<WebhookSubscription>("webhooks");
+
+    let subs: Vec<WebhookView> = sub_coll
+        .find(doc! {})
+        .sort(doc! { "created_at": 1 })
+        .await?
+        .map_ok(WebhookView::from)
+        .try_collect()
+        .await?;
+
+    Ok(HttpResponse::Ok().json(subs))
+}
+
+#[get("/webhooks/{id}")]
+pub async fn get_webhook(
+    db: web::Data<Database>,
+    path: web::Path<WebhookId>,
+) -> AppResult<impl Responder> {
+    let sub_id = path.into_inner();
+    let sub_coll = db.collection::<WebhookSubscription>("webhooks");
+
+    let sub = sub_coll
+        .find_one(doc! { "_id": &sub_id })
+        .await?
+        .ok_or_else(|| AppError::not_found("webhook_not_found", "Webhook not found"))?;
+
+    Ok(HttpResponse::Ok().json(WebhookView::from(sub)))
+}
+
+/// Deletes the subscription. Deliveries still waiting for a retry become
+/// dead letters so they can be inspected, but not redelivered.
+#[delete("/webhooks/{id}")]
+pub async fn delete_webhook(
+    db: web::Data<Database>,
+    path: web::Path<WebhookId>,
+) -> AppResult<impl Responder> {
+    let sub_id = path.into_inner();
+    let sub_coll = db.collection::<WebhookSubscription>("webhooks");
+    let delivery_coll = db.collection::<WebhookDelivery>("webhook_deliveries");
+
+    let deleted = sub_coll
+        .delete_one(doc! { "_id": &sub_id })
+        .await?;
+    if deleted.deleted_count == 0 {
+        return Err(AppError::not_found("webhook_not_found", "Webhook not found"));
+    }
+
+    delivery_coll
+        .update_many(
+            doc! { "subscription_id": &sub_id, "status": "pending" },
+            doc! { "$set": {
+                "status": "dead",
+                "next_attempt_at": null,
+                "updated_at": BsonDateTime::now()
+            } },
+        )
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/webhooks/{id}/deliveries")]
+pub async fn get_webhook_deliveries(
+    db: web::Data<Database>,
+    path: web::Path<WebhookId>,
+    query: web::Query<WebhookDeliveryQuery>,
+) -> AppResult<impl Responder> {
+    let sub_id = path.into_inner();
+    let delivery_coll = db.collection::<WebhookDelivery>("webhook_deliveries");
+
+    let mut filter = doc! { "subscription_id": &sub_id };
+    if let Some(status) = query.status {
+        let status_bson = bson::to_bson(&status)?;
+        filter.insert("status", status_bson);
+    }
+
+    let deliveries: Vec<WebhookDelivery> = delivery_coll
+        .find(filter)
+        .sort(doc! { "created_at": -1 })
+        .limit(DELIVERY_PAGE)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(HttpResponse::Ok().json(deliveries))
+}
+
+/// Deliveries that used up their attempts, newest first.
+#[get("/webhook-deliveries/dead")]
+pub async fn get_dead_letters(
+    db: web::Data<Database>,
+    query: web::Query<DeadLetterQuery>,
+) -> AppResult<impl Responder> {
+    let delivery_coll = db.collection::<WebhookDelivery>("webhook_deliveries");
+
+    let mut filter = doc! { "status": "dead" };
+    if let Some(sub_id) = &query.subscription_id {
+        filter.insert("subscription_id", *sub_id);
+    }
+
+    let deliveries: Vec<WebhookDelivery> = delivery_coll
+        .find(filter)
+        .sort(doc! { "updated_at": -1 })
+        .limit(DELIVERY_PAGE)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(HttpResponse::Ok().json(deliveries))
+}
+
+#[get("/webhook-deliveries/{id}")]
+pub async fn get_webhook_delivery(
+    db: web::Data<Database>,
+    path: web::Path<WebhookDeliveryId>,
+) -> AppResult<impl Responder> {
+    let delivery_id = path.into_inner();
+    let delivery_coll = db.collection::<WebhookDelivery>("webhook_deliveries");
+
+    let delivery = delivery_coll
+        .find_one(doc! { "_id": &delivery_id })
+        .await?
+        .ok_or_else(|| AppError::not_found("delivery_not_found", "Delivery not found"))?;
+
+    Ok(HttpResponse::Ok().json(delivery))
+}
+
+/// Queues a finished delivery again with a fresh set of attempts. The
+/// dispatcher picks it up on its next pass.
+#[post("/webhook-deliveries/{id}/redeliver")]
+pub async fn redeliver_webhook_delivery(
+    db: web::Data<Database>,
+    path: web::Path<WebhookDeliveryId>,
+) -> AppResult<impl Responder> {
+    let delivery_id = path.into_inner();
+    let sub_coll = db.collection::<WebhookSubscription>("webhooks");
+    let delivery_coll = db.collection::<WebhookDelivery>("webhook_deliveries");
+
+    let delivery = delivery_coll
+        .find_one(doc! { "_id": &delivery_id })
+        .await?
+        .ok_or_else(|| AppError::not_found("delivery_not_found", "Delivery not found"))?;
+
+    sub_coll
+        .find_one(doc! { "_id": &delivery.subscription_id })
+        .await?
+        .ok_or_else(|| AppError::gone("webhook_deleted", "Webhook was deleted"))?;
+
+    let delivery = delivery_coll
+        .find_one_and_update(
+            doc! { "_id": &delivery_id, "status": { "$ne": "pending" } },
+            doc! { "$set": {
+                "status": "pending",
+                "attempts": 0_i64,
+                "next_attempt_at": BsonDateTime::now(),
+                "updated_at": BsonDateTime::now()
+            } },
+        )
+        .return_document(ReturnDocument::After)
+        .await?
+        .ok_or_else(|| AppError::conflict("delivery_pending", "Delivery is already pending"))?;
+
+    Ok(HttpResponse::Accepted().json(delivery))
+}
@@ -0,0 +1,405 @@
+//! WebSocket gateway for chat clients.
+//!
+//! Clients connect with `GET /ws?participant_id=<uuid>` and exchange JSON text
+//! frames, each an object with a `type` field. Frames carrying an optional
+//! `request_id` get it echoed back on the matching reply or error. With a
+//! participant token the id must be the token's subject, and messages can
+//! only be sent to conversations the participant is a member of. Browsers,
+//! which cannot set headers here, pass the token as `access_token`.
+//!
+//! Client to server:
+//!
+//! | type          | fields                                                              |
+//! |---------------|---------------------------------------------------------------------|
+//! | `subscribe`   | `conversation_ids`                                                  |
+//! | `unsubscribe` | `conversation_ids`                                                  |
+//! | `send_message`| `conversation_id`, `content`, `channel`?, `external_id`?, `sent_at`? |
+//! | `mark_read`   | `conversation_id`, `read_at`?                                       |
+//! | `typing`      | `conversation_id`, `typing`                                         |
+//! | `ping`        |                                                                     |
+//!
+//! Server to client:
+//!
+//! | type           | fields                                                  |
+//! |----------------|---------------------------------------------------------|
+//! | `subscribed`   | `conversation_ids` (the full current subscription set)  |
+//! | `message_sent` | `message` (`duplicate` set for a repeated external id)  |
+//! | `read_marked`  | `conversation_id`, `conversation`                       |
+//! | `event`        | `event` (same payload as the server-sent event streams) |
+//! | `typing`       | `conversation_id`, `participant_id`, `typing`           |
+//! | `pong`         |                                                         |
+//! | `error`        | `error` (a problem object like HTTP error bodies)       |
+//!
+//! Messages are sent as the connected participant through the same code path
+//! as `POST /messages`, so membership rules, reply jobs and events apply.
+//!
+//! Events are delivered in sequence. When live events were skipped, because
+//! the connection fell behind or another instance wrote the ones in between,
+//! the subscribed conversations' events are replayed from the event log
+//! first, the same way the server-sent event streams catch up.
+//!
+//! A subscription ends with the membership it was granted for: the event
+//! removing the participant is still delivered, followed by an unsolicited
+//! `subscribed` frame without the conversation.
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use actix_web::{get, web, HttpRequest, Responder};
+use actix_ws::{AggregatedMessage, Session};
+use bson::doc;
+use chrono::Utc;
+use futures::StreamExt;
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use super::{insert_message, mark_read, CreateMessagePayload, StoredMessage};
+use crate::auth::{self, Principal};
+use crate::config::LimitsConfig;
+use crate::error::{AppError, AppResult, Problem};
+use crate::events::{Event, EventBus, TypingSignal};
+use crate::ids::{ConversationId, ParticipantId};
+use crate::models::{Conversation, Participant};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(90);
+const DEFAULT_CHANNEL: &str = "chat";
+const REPLAY_PAGE: i64 = 500;
+
+#[derive(Deserialize)]
+pub struct WebSocketQuery {
+    pub participant_id: ParticipantId,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrame {
+    Subscribe {
+        request_id: Option<String>,
+        conversation_ids: Vec<ConversationId>,
+    },
+    Unsubscribe {
+        request_id: Option<String>,
+        conversation_ids: Vec<ConversationId>,
+    },
+    SendMessage {
+        request_id: Option<String>,
+        conversation_id: ConversationId,
+        channel: Option<String>,
+        external_id: Option<String>,
+        sent_at: Option<chrono::DateTime<Utc>>,
+        content: String,
+    },
+    MarkRead {
+        request_id: Option<String>,
+        conversation_id: ConversationId,
+        read_at: Option<chrono::DateTime<Utc>>,
+    },
+    Typing {
+        conversation_id: ConversationId,
+        typing: bool,
+    },
+    Ping {
+        request_id: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerFrame<'a> {
+    Subscribed {
+        request_id: Option<String>,
+        conversation_ids: Vec<ConversationId>,
+    },
+    MessageSent {
+        request_id: Option<String>,
+        message: StoredMessage,
+    },
+    ReadMarked {
+        request_id: Option<String>,
+        conversation_id: ConversationId,
+        conversation: Conversation,
+    },
+    Event {
+        event: &'a Event,
+    },
+    Typing(&'a TypingSignal),
+    Pong {
+        request_id: Option<String>,
+    },
+    Error {
+        request_id: Option<String>,
+        error: Problem,
+    },
+}
+
+impl ServerFrame<'_> {
+    fn error(request_id: Option<String>, err: AppError) -> Self {
+        ServerFrame::Error { request_id, error: err.problem() }
+    }
+}
+
+async fn send_frame(session: &mut Session, frame: &ServerFrame<'_>) -> Result<(), actix_ws::Closed> {
+    match serde_json::to_string(frame) {
+        Ok(text) => session.text(text).await,
+        Err(e) => {
+            eprintln!("Failed to serialize websocket frame: {e}");
+            Ok(())
+        }
+    }
+}
+
+struct Connection {
+    db: Database,
+    events: EventBus,
+    participant_id: ParticipantId,
+    principal: Option<Principal>,
+    subscriptions: HashSet<ConversationId>,
+    /// Sequence number of the last event taken from the bus or the log.
+    last_id: u64,
+}
+
+impl Connection {
+    /// Subscribing requires an active membership in every listed conversation.
+    async fn subscribe(&mut self, conversation_ids: Vec<ConversationId>) -> AppResult<()> {
+        let conv_coll = self.db.collection::<Conversation>("conversations");
+        for conv_id in &conversation_ids {
+            let conv = conv_coll
+                .find_one(doc! { "_id": conv_id })
+                .await?
+                .ok_or_else(|| AppError::not_found("conversation_not_found", "Conversation not found"))?;
+
+            if conv.membership(&self.participant_id).is_none_or(|cp| cp.left_at.is_some()) {
+                return Err(AppError::forbidden("not_a_member", "Not a member of this conversation"));
+            }
+        }
+        self.subscriptions.extend(conversation_ids);
+        Ok(())
+    }
+
+    /// Forwards an event of a subscribed conversation and ends the
+    /// subscription when the event ends the membership.
+    async fn deliver(&mut self, session: &mut Session, event: &Event) -> Result<(), actix_ws::Closed> {
+        let Some(conv_id) = event.conversation_id.filter(|id| self.subscriptions.contains(id)) else {
+            return Ok(());
+        };
+        send_frame(session, &ServerFrame::Event { event }).await?;
+        if event.ends_membership(self.participant_id) {
+            self.subscriptions.remove(&conv_id);
+            let frame = ServerFrame::Subscribed { request_id: None, conversation_ids: self.subscribed_ids() };
+            send_frame(session, &frame).await?;
+        }
+        Ok(())
+    }
+
+    /// Replays the subscribed conversations' events after `last_id` from the
+    /// log. A failed read is retried with the next event or heartbeat.
+    async fn catch_up(&mut self, session: &mut Session) -> Result<(), actix_ws::Closed> {
+        loop {
+            let filter = doc! { "conversation_id": { "$in": self.subscribed_ids() } };
+            let page = match self.events.since(self.last_id, filter, REPLAY_PAGE).await {
+                Ok(page) => page,
+                Err(e) => {
+                    eprintln!("Failed to replay websocket events: {e}");
+                    return Ok(());
+                }
+            };
+            for event in &page.events {
+                if event.id > self.last_id {
+                    self.last_id = event.id;
+                    self.deliver(session, event).await?;
+                }
+            }
+            self.last_id = self.last_id.max(page.next_since);
+            // A short page reached the end of what can be read.
+            if (page.events.len() as i64) < REPLAY_PAGE {
+                return Ok(());
+            }
+        }
+    }
+
+    fn subscribed_ids(&self) -> Vec<ConversationId> {
+        self.subscriptions.iter().copied().collect()
+    }
+
+    async fn handle(&mut self, frame: ClientFrame) -> Option<ServerFrame<'static>> {
+        match frame {
+            ClientFrame::Subscribe { request_id, conversation_ids } => {
+                Some(match self.subscribe(conversation_ids).await {
+                    Ok(()) => ServerFrame::Subscribed { request_id, conversation_ids: self.subscribed_ids() },
+                    Err(e) => ServerFrame::error(request_id, e),
+                })
+            }
+            ClientFrame::Unsubscribe { request_id, conversation_ids } => {
+                for conv_id in &conversation_ids {
+                    self.subscriptions.remove(conv_id);
+                }
+                Some(ServerFrame::Subscribed { request_id, conversation_ids: self.subscribed_ids() })
+            }
+            ClientFrame::SendMessage { request_id, conversation_id, channel, external_id, sent_at, content } => {
+                if let Err(e) = auth::authorize_conversation(&self.db, self.principal, conversation_id).await {
+                    return Some(ServerFrame::error(request_id, e));
+                }
+                let payload = CreateMessagePayload {
+                    conversation_id,
+                    sender_id: Some(self.participant_id),
+                    sender_address: None,
+                    channel: channel.unwrap_or_else(|| DEFAULT_CHANNEL.to_string()),
+                    external_id,
+                    sent_at: sent_at.unwrap_or_else(Utc::now),
+                    content,
+                    summary: None,
+                    context: None,
+                };
+                let sent = insert_message(&self.db, &self.events, payload, None, Some(self.participant_id)).await;
+                Some(match sent {
+                    Ok(message) => ServerFrame::MessageSent { request_id, message },
+                    Err(e) => ServerFrame::error(request_id, e),
+                })
+            }
+            ClientFrame::MarkRead { request_id, conversation_id, read_at } => {
+                Some(match mark_read(&self.db, &self.events, self.participant_id, conversation_id, read_at).await {
+                    Ok(conversation) => ServerFrame::ReadMarked { request_id, conversation_id, conversation },
+                    Err(e) => ServerFrame::error(request_id, e),
+                })
+            }
+            ClientFrame::Typing { conversation_id, typing } => {
+                if !self.subscriptions.contains(&conversation_id) {
+                    return Some(ServerFrame::error(
+                        None,
+                        AppError::forbidden("not_subscribed", "Subscribe to the conversation first"),
+                    ));
+                }
+                self.events.publish_typing(TypingSignal {
+                    conversation_id,
+                    participant_id: self.participant_id,
+                    typing,
+                });
+                None
+            }
+            ClientFrame::Ping { request_id } => Some(ServerFrame::Pong { request_id }),
+        }
+    }
+}
+
+#[get("/ws")]
+pub async fn websocket(
+    db: web::Data<Database>,
+    events: web::Data<EventBus>,
+    limits: web::Data<LimitsConfig>,
+    principal: Option<web::ReqData<Principal>>,
+    req: HttpRequest,
+    body: web::Payload,
+    query: web::Query<WebSocketQuery>,
+) -> AppResult<impl Responder> {
+    let participant_id = query.into_inner().participant_id;
+    let principal = principal.map(web::ReqData::into_inner);
+    if principal.and_then(Principal::participant).is_some_and(|caller| caller != participant_id) {
+        return Err(AppError::forbidden("not_yourself", "Participants can only connect as themselves"));
+    }
+
+    db.collection::<Participant>("participants")
+        .find_one(doc! { "_id": participant_id })
+        .await?
+        .ok_or_else(|| AppError::not_found("participant_not_found", "Participant not found"))?;
+
+    let last_id = events
+        .head()
+        .await?;
+
+    let (response, session, msg_stream) = actix_ws::handle(&req, body)
+        .map_err(|e| AppError::bad_request("websocket_handshake", e.to_string()))?;
+
+    let conn = Connection {
+        db: db.get_ref().clone(),
+        events: events.get_ref().clone(),
+        participant_id,
+        principal,
+        subscriptions: HashSet::new(),
+        last_id,
+    };
+    let msg_stream = msg_stream
+        .max_frame_size(limits.websocket_frame_bytes)
+        .aggregate_continuations()
+        .max_continuation_size(limits.websocket_frame_bytes);
+    actix_web::rt::spawn(run_connection(conn, session, msg_stream));
+
+    Ok(response)
+}
+
+async fn run_connection(
+    mut conn: Connection,
+    mut session: Session,
+    mut msg_stream: actix_ws::AggregatedMessageStream,
+) {
+    let mut event_rx = conn.events.subscribe();
+    let mut typing_rx = conn.events.subscribe_typing();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    let close_reason = loop {
+        let result = tokio::select! {
+            msg = msg_stream.next() => {
+                last_seen = Instant::now();
+                match msg {
+                    Some(Ok(AggregatedMessage::Text(text))) => {
+                        let reply = match serde_json::from_str::<ClientFrame>(&text) {
+                            Ok(frame) => conn.handle(frame).await,
+                            Err(e) => Some(ServerFrame::error(None, AppError::bad_request("invalid_frame", e.to_string()))),
+                        };
+                        match reply {
+                            Some(frame) => send_frame(&mut session, &frame).await,
+                            None => Ok(()),
+                        }
+                    }
+                    Some(Ok(AggregatedMessage::Binary(_))) => {
+                        let err = AppError::bad_request("unsupported_frame", "Binary frames are not supported");
+                        send_frame(&mut session, &ServerFrame::error(None, err)).await
+                    }
+                    Some(Ok(AggregatedMessage::Ping(bytes))) => session.pong(&bytes).await,
+                    Some(Ok(AggregatedMessage::Pong(_))) => Ok(()),
+                    Some(Ok(AggregatedMessage::Close(reason))) => break reason,
+                    Some(Err(_)) | None => break None,
+                }
+            }
+            event = event_rx.recv() => match event {
+                Ok(event) if event.id <= conn.last_id => Ok(()),
+                Ok(event) if event.id == conn.last_id + 1 => {
+                    conn.last_id = event.id;
+                    conn.deliver(&mut session, &event).await
+                }
+                // Missed the events in between or fell behind the live
+                // channel, catch up from the log.
+                Ok(_) | Err(RecvError::Lagged(_)) => conn.catch_up(&mut session).await,
+                Err(RecvError::Closed) => break None,
+            },
+            signal = typing_rx.recv() => match signal {
+                Ok(signal) if signal.participant_id != conn.participant_id
+                    && conn.subscriptions.contains(&signal.conversation_id) =>
+                {
+                    send_frame(&mut session, &ServerFrame::Typing(&signal)).await
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => Ok(()),
+                Err(RecvError::Closed) => break None,
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > CLIENT_TIMEOUT {
+                    break None;
+                }
+                // Picks up events written elsewhere while it was quiet.
+                match conn.catch_up(&mut session).await {
+                    Ok(()) => session.ping(b"").await,
+                    closed => closed,
+                }
+            }
+        };
+
+        if result.is_err() {
+            // The client is gone, nothing left to close.
+            return;
+        }
+    };
+
+    let _ = session.close(close_reason).await;
+}
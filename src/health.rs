@@ -0,0 +1,75 @@
+//! Startup state and readiness.
+//!
+//! The HTTP server comes up before MongoDB is known to be reachable. Until
+//! the connection is established and the startup tasks (migrations, index
+//! sync) are done, API requests are answered with 503 and `/health/ready`
+//! says which stage startup is in.
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use bson::doc;
+use mongodb::Database;
+use serde::Serialize;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq, Eq)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum Startup {
+    /// Waiting for MongoDB to answer.
+    #[default]
+    Connecting,
+    Migrating,
+    Ready,
+    /// A startup task failed, see the log. Requests stay rejected.
+    Failed,
+}
+
+#[derive(Clone, Default)]
+pub struct Health {
+    startup: Arc<RwLock<Startup>>,
+}
+
+impl Health {
+    pub fn startup(&self) -> Startup {
+        self.startup.read().map(|s| *s).unwrap_or(Startup::Failed)
+    }
+
+    pub fn set(&self, stage: Startup) {
+        if let Ok(mut startup) = self.startup.write() {
+            *startup = stage;
+        }
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.startup() == Startup::Ready
+    }
+}
+
+pub async fn ping(db: &Database, timeout: Duration) -> Result<(), String> {
+    match tokio::time::timeout(timeout, db.run_command(doc! { "ping": 1 })).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no answer within {}s", timeout.as_secs())),
+    }
+}
+
+/// Pings until MongoDB answers, backing off exponentially between attempts.
+/// Gives up after `max_attempts` if there is a limit.
+pub async fn wait_for_database(db: &Database, hosts: &str, max_attempts: Option<u32>) -> Result<(), String> {
+    let mut delay = INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let Err(e) = ping(db, CONNECT_TIMEOUT).await else { return Ok(()) };
+        if max_attempts.is_some_and(|max| attempt >= max) {
+            return Err(format!("MongoDB at {hosts} is not reachable after {attempt} attempts: {e}"));
+        }
+        eprintln!("MongoDB at {hosts} is not reachable (attempt {attempt}): {e}; retrying in {}s", delay.as_secs());
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_BACKOFF);
+        attempt += 1;
+    }
+}
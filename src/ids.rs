@@ -0,0 +1,75 @@
+//! Typed entity ids.
+//!
+//! Every id is a UUID, but each entity has its own type so that a
+//! conversation id cannot end up where a participant id is expected. Ids are
+//! always serialized as hyphenated strings: `Uuid` itself is written as
+//! binary by serializers that are not human-readable, which is what
+//! `insert_one` uses, so the stored form used to depend on the write path.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+use bson::Bson;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use uuid::Uuid;
+
+macro_rules! entity_id {
+    ($($(#[$meta:meta])* $name:ident),* $(,)?) => {$(
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub struct $name(Uuid);
+
+        impl $name {
+            pub fn generate() -> Self {
+                $name(Uuid::new_v4())
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.hyphenated().fmt(f)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = uuid::Error;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Uuid::parse_str(s).map($name)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = Cow::<str>::deserialize(deserializer)?;
+                s.parse().map_err(|_| de::Error::custom(format!("invalid {}: {s}", stringify!($name))))
+            }
+        }
+
+        impl From<$name> for Bson {
+            fn from(id: $name) -> Bson {
+                Bson::String(id.to_string())
+            }
+        }
+    )*};
+}
+
+entity_id! {
+    ParticipantId,
+    ConversationId,
+    MessageId,
+    MessageSummaryId,
+    AgentJobId,
+    ParticipantMergeId,
+    AiConfigVersionId,
+    WebhookId,
+    WebhookDeliveryId,
+    ApiKeyId,
+}
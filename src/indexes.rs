@@ -0,0 +1,246 @@
+//! Declared indexes of every collection.
+//!
+//! [`sync`] compares the declaration with what the database has: missing
+//! indexes are created (unless it is a dry run), indexes whose definition
+//! differs are reported as drift and left alone, since rebuilding them can be
+//! expensive, and indexes nobody declared are listed as unmanaged.
+
+use bson::{doc, Bson, Document};
+use futures::TryStreamExt;
+use mongodb::{
+    error::{ErrorKind, WriteFailure},
+    options::IndexOptions,
+    Database,
+    IndexModel,
+};
+
+/// Server error code for a write rejected by a unique index.
+pub const DUPLICATE_KEY: i32 = 11000;
+
+/// Whether a single-document write was rejected by a unique index. Inserts
+/// and updates report it as a write error, `findAndModify` as a command
+/// error.
+pub fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    match *err.kind {
+        ErrorKind::Write(WriteFailure::WriteError(ref e)) => e.code == DUPLICATE_KEY,
+        ErrorKind::Command(ref e) => e.code == DUPLICATE_KEY,
+        _ => false,
+    }
+}
+
+pub struct IndexSpec {
+    pub collection: &'static str,
+    pub name: &'static str,
+    pub keys: Document,
+    pub unique: bool,
+    pub partial: Option<Document>,
+}
+
+impl IndexSpec {
+    fn new(collection: &'static str, name: &'static str, keys: Document) -> Self {
+        IndexSpec { collection, name, keys, unique: false, partial: None }
+    }
+
+    fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    fn partial(mut self, filter: Document) -> Self {
+        self.partial = Some(filter);
+        self
+    }
+
+    fn model(&self) -> IndexModel {
+        let options = IndexOptions::builder()
+            .name(self.name.to_string())
+            .unique(self.unique.then_some(true))
+            .partial_filter_expression(self.partial.clone())
+            .build();
+        IndexModel::builder().keys(self.keys.clone()).options(options).build()
+    }
+
+    /// Differences to an existing index of the same name, empty if none.
+    fn drift(&self, existing: &IndexModel) -> Vec<String> {
+        let mut drift = Vec::new();
+        if !same_keys(&self.keys, &existing.keys) {
+            drift.push(format!("keys {} instead of {}", existing.keys, self.keys));
+        }
+        let options = existing.options.as_ref();
+        let unique = options.and_then(|o| o.unique).unwrap_or(false);
+        if unique != self.unique {
+            drift.push(format!("unique is {unique}, declared {}", self.unique));
+        }
+        let partial = options.and_then(|o| o.partial_filter_expression.as_ref());
+        if partial != self.partial.as_ref() {
+            drift.push("partial filter differs".to_string());
+        }
+        drift
+    }
+}
+
+/// Key order matters for compound indexes and the server may report
+/// directions with a different numeric type than they were declared with.
+fn same_keys(declared: &Document, existing: &Document) -> bool {
+    fn direction(value: &Bson) -> Option<f64> {
+        match value {
+            Bson::Int32(v) => Some(*v as f64),
+            Bson::Int64(v) => Some(*v as f64),
+            Bson::Double(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    declared.len() == existing.len()
+        && declared.iter().zip(existing.iter()).all(|((dk, dv), (ek, ev))| {
+            dk == ek && match (direction(dv), direction(ev)) {
+                (Some(d), Some(e)) => d == e,
+                _ => dv == ev,
+            }
+        })
+}
+
+pub fn required() -> Vec<IndexSpec> {
+    vec![
+        // Address ownership is exclusive, the lookup by value alone uses the prefix.
+        IndexSpec::new("participants", "addresses_unique", doc! { "addresses.value": 1, "addresses.kind": 1 })
+            .unique()
+            .partial(doc! { "addresses.value": { "$exists": true } }),
+        IndexSpec::new("conversations", "external_id_unique", doc! { "external_id": 1 })
+            .unique(),
+        IndexSpec::new("conversations", "participants_last_activity", doc! {
+            "participants.participant_id": 1,
+            "last_message_at": -1
+        }),
+        IndexSpec::new("messages", "conversation_sent_at", doc! { "conversation_id": 1, "sent_at": 1 }),
+        IndexSpec::new("messages", "sender_id", doc! { "sender_id": 1 }),
+        IndexSpec::new("messages", "channel_external_id_unique", doc! { "channel": 1, "external_id": 1 })
+            .unique()
+            .partial(doc! { "external_id": { "$type": "string" } }),
+        IndexSpec::new("messages", "idempotency_unique", doc! { "idempotency.scope": 1, "idempotency.key": 1 })
+            .unique()
+            .partial(doc! { "idempotency.key": { "$type": "string" } }),
+        IndexSpec::new("message_summaries", "conversation_id", doc! { "conversation_id": 1 }),
+        IndexSpec::new("participant_merges", "target_merged_at", doc! { "target_id": 1, "merged_at": -1 }),
+        IndexSpec::new("ai_config_versions", "participant_version_unique", doc! {
+            "participant_id": 1,
+            "config.version": 1
+        })
+            .unique(),
+        IndexSpec::new("agent_jobs", "status_created_at", doc! { "status": 1, "created_at": 1 }),
+        IndexSpec::new("agent_jobs", "participant_status", doc! { "participant_id": 1, "status": 1 }),
+        IndexSpec::new("events", "conversation_seq", doc! { "conversation_id": 1, "_id": 1 }),
+        IndexSpec::new("events", "participant_seq", doc! { "participant_ids": 1, "_id": 1 }),
+        IndexSpec::new("webhook_deliveries", "status_due", doc! { "status": 1, "next_attempt_at": 1 }),
+        // Dispatchers going over the same events enqueue each delivery once.
+        IndexSpec::new("webhook_deliveries", "subscription_event_unique", doc! {
+            "subscription_id": 1,
+            "event._id": 1
+        })
+            .unique(),
+        IndexSpec::new("webhook_deliveries", "subscription_created_at", doc! {
+            "subscription_id": 1,
+            "created_at": -1
+        }),
+        IndexSpec::new("api_keys", "key_hash_unique", doc! { "key_hash": 1 })
+            .unique(),
+    ]
+}
+
+#[derive(Debug)]
+pub enum IndexStatus {
+    Present,
+    Created,
+    /// Would be created, only reported in dry runs.
+    Missing,
+    Drifted(Vec<String>),
+    /// Creation was rejected, e.g. a unique index over duplicate values.
+    Failed(String),
+}
+
+#[derive(Debug, Default)]
+pub struct IndexReport {
+    pub indexes: Vec<(&'static str, &'static str, IndexStatus)>,
+    /// Indexes in the database that are not declared, as (collection, name).
+    pub unmanaged: Vec<(String, String)>,
+}
+
+impl IndexReport {
+    pub fn is_clean(&self) -> bool {
+        self.unmanaged.is_empty()
+            && self.indexes.iter().all(|(_, _, status)| matches!(status, IndexStatus::Present | IndexStatus::Created))
+    }
+}
+
+async fn existing_indexes(db: &Database, collection: &str) -> mongodb::error::Result<Vec<IndexModel>> {
+    db.collection::<Document>(collection)
+        .list_indexes()
+        .await?
+        .try_collect()
+        .await
+}
+
+pub async fn sync(db: &Database, dry_run: bool) -> mongodb::error::Result<IndexReport> {
+    let specs = required();
+    let mut report = IndexReport::default();
+
+    let mut collections: Vec<&'static str> = Vec::new();
+    for spec in &specs {
+        if !collections.contains(&spec.collection) {
+            collections.push(spec.collection);
+        }
+    }
+
+    // Listing the indexes of a collection that does not exist yet is an error.
+    let present = db.list_collection_names().await?;
+
+    for collection in collections {
+        let existing = if present.iter().any(|n| n == collection) {
+            existing_indexes(db, collection).await?
+        } else {
+            Vec::new()
+        };
+        let name_of = |model: &IndexModel| model.options.as_ref().and_then(|o| o.name.clone());
+
+        for spec in specs.iter().filter(|s| s.collection == collection) {
+            let status = match existing.iter().find(|m| name_of(m).as_deref() == Some(spec.name)) {
+                Some(model) => {
+                    let drift = spec.drift(model);
+                    if drift.is_empty() { IndexStatus::Present } else { IndexStatus::Drifted(drift) }
+                }
+                None if dry_run => IndexStatus::Missing,
+                None => match db.collection::<Document>(collection).create_index(spec.model()).await {
+                    Ok(_) => IndexStatus::Created,
+                    Err(e) => IndexStatus::Failed(e.to_string()),
+                },
+            };
+            report.indexes.push((collection, spec.name, status));
+        }
+
+        for model in &existing {
+            let Some(name) = name_of(model) else { continue };
+            if name != "_id_" && !specs.iter().any(|s| s.collection == collection && s.name == name) {
+                report.unmanaged.push((collection.to_string(), name));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+pub fn print_report(report: &IndexReport) {
+    for (collection, name, status) in &report.indexes {
+        match status {
+            IndexStatus::Present => {}
+            IndexStatus::Created => println!("Created index {collection}.{name}"),
+            IndexStatus::Missing => println!("Missing index {collection}.{name}, would be created"),
+            IndexStatus::Drifted(drift) => {
+                eprintln!("Index {collection}.{name} differs from its declaration: {}", drift.join("; "));
+            }
+            IndexStatus::Failed(e) => eprintln!("Failed to create index {collection}.{name}: {e}"),
+        }
+    }
+    for (collection, name) in &report.unmanaged {
+        eprintln!("Index {collection}.{name} is not declared by maratus");
+    }
+}
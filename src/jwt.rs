@@ -0,0 +1,118 @@
+//! Verification of participant tokens.
+//!
+//! HS256 tokens are checked against the shared secret, RS256 tokens against
+//! the public keys of a local JWKS file, chosen by the `kid` header. A file
+//! with a single key also accepts tokens without `kid`. The keys are loaded
+//! once at startup; restart to pick up a rotated JWKS file.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, JwkSet, KeyAlgorithm},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+
+use crate::config::JwtConfig;
+use crate::error::AppError;
+use crate::ids::ParticipantId;
+
+pub struct JwtVerifier {
+    hs256: Option<DecodingKey>,
+    rs256: HashMap<String, DecodingKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway_secs: u64,
+}
+
+#[derive(Debug)]
+pub struct JwksError(String);
+
+impl fmt::Display for JwksError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for JwksError {}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+impl JwtVerifier {
+    /// `None` when no key is configured, tokens are then rejected.
+    pub fn from_config(config: &JwtConfig) -> Result<Option<JwtVerifier>, JwksError> {
+        let hs256 = config.hs256_secret.as_ref().map(|secret| DecodingKey::from_secret(secret.as_bytes()));
+
+        let mut rs256 = HashMap::new();
+        if let Some(path) = &config.jwks_file {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| JwksError(format!("cannot read JWKS file {}: {e}", path.display())))?;
+            let set: JwkSet = serde_json::from_str(&text)
+                .map_err(|e| JwksError(format!("invalid JWKS file {}: {e}", path.display())))?;
+            for jwk in &set.keys {
+                let rsa = matches!(jwk.algorithm, AlgorithmParameters::RSA(_));
+                let usable = jwk.common.key_algorithm.is_none_or(|alg| alg == KeyAlgorithm::RS256);
+                if !rsa || !usable {
+                    eprintln!("Skipping JWKS key {:?}, only RS256 keys are supported", jwk.common.key_id);
+                    continue;
+                }
+                let key = DecodingKey::from_jwk(jwk)
+                    .map_err(|e| JwksError(format!("invalid key {:?} in {}: {e}", jwk.common.key_id, path.display())))?;
+                rs256.insert(jwk.common.key_id.clone().unwrap_or_default(), key);
+            }
+            if rs256.is_empty() {
+                return Err(JwksError(format!("JWKS file {} has no RS256 keys", path.display())));
+            }
+        }
+
+        if hs256.is_none() && rs256.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(JwtVerifier {
+            hs256,
+            rs256,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            leeway_secs: config.leeway_secs,
+        }))
+    }
+
+    /// Checks signature, expiry, issuer and audience, and returns the
+    /// participant the token was issued to.
+    pub fn verify(&self, token: &str) -> Result<ParticipantId, AppError> {
+        let invalid = |detail: String| AppError::unauthorized("invalid_token", detail);
+
+        let header = jsonwebtoken::decode_header(token).map_err(|e| invalid(format!("Malformed token: {e}")))?;
+        let key = match header.alg {
+            Algorithm::HS256 => self.hs256.as_ref(),
+            Algorithm::RS256 => match &header.kid {
+                Some(kid) => self.rs256.get(kid),
+                None if self.rs256.len() == 1 => self.rs256.values().next(),
+                None => None,
+            },
+            _ => None,
+        };
+        let key = key.ok_or_else(|| invalid(format!("No key to verify a {:?} token with this kid", header.alg)))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.leeway_secs;
+        validation.validate_nbf = true;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let data = jsonwebtoken::decode::<Claims>(token, key, &validation)
+            .map_err(|e| invalid(format!("Token rejected: {e}")))?;
+        data.claims.sub.parse()
+            .map_err(|_| invalid("Token subject is not a participant id".to_string()))
+    }
+}
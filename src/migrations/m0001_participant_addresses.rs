@@ -0,0 +1,43 @@
+//! Moves the legacy single `address` of participants into `addresses`.
+
+use bson::{doc, Document};
+use futures::{future::BoxFuture, FutureExt, TryStreamExt};
+use mongodb::Database;
+
+use crate::models::{AddressKind, ParticipantAddress};
+
+pub fn run(db: &Database, dry_run: bool) -> BoxFuture<'_, mongodb::error::Result<u64>> {
+    backfill(db, dry_run).boxed()
+}
+
+async fn backfill(db: &Database, dry_run: bool) -> mongodb::error::Result<u64> {
+    let part_coll = db.collection::<Document>("participants");
+    let legacy = doc! { "address": { "$type": "string" }, "addresses": { "$exists": false } };
+
+    if dry_run {
+        return part_coll.count_documents(legacy).await;
+    }
+
+    let mut cursor = part_coll.find(legacy).await?;
+    let mut updated = 0;
+    while let Some(legacy) = cursor.try_next().await? {
+        let (Ok(id), Ok(value)) = (legacy.get_str("_id"), legacy.get_str("address")) else {
+            continue;
+        };
+
+        let address = ParticipantAddress { kind: AddressKind::infer(value), value: value.to_string() };
+
+        part_coll
+            .update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$set": { "addresses": [bson::to_bson(&address)?] },
+                    "$unset": { "address": "" }
+                },
+            )
+            .await?;
+        updated += 1;
+    }
+
+    Ok(updated)
+}
@@ -0,0 +1,66 @@
+//! Rewrites stored addresses into their normalized form. Participants whose
+//! addresses only collide after normalization are logged rather than merged,
+//! since folding them together is a decision for the merge endpoint.
+//!
+//! Works on raw documents: it runs before ids are migrated, so participants
+//! may not yet fit the current model.
+
+use std::collections::{HashMap, HashSet};
+
+use bson::{doc, Bson, Document};
+use futures::{future::BoxFuture, FutureExt, TryStreamExt};
+use mongodb::Database;
+
+use crate::models::ParticipantAddress;
+use crate::normalize::normalize_address;
+
+pub fn run(db: &Database, dry_run: bool) -> BoxFuture<'_, mongodb::error::Result<u64>> {
+    normalize(db, dry_run).boxed()
+}
+
+async fn normalize(db: &Database, dry_run: bool) -> mongodb::error::Result<u64> {
+    let part_coll = db.collection::<Document>("participants");
+    let mut owners: HashMap<ParticipantAddress, Vec<Bson>> = HashMap::new();
+    let mut updated = 0;
+
+    let mut cursor = part_coll.find(doc! { "addresses": { "$type": "array" } }).await?;
+    while let Some(part) = cursor.try_next().await? {
+        let Some(id) = part.get("_id").cloned() else { continue };
+        let addresses: Vec<ParticipantAddress> = bson::from_bson(part.get("addresses").cloned().unwrap_or(Bson::Null))?;
+
+        let mut seen = HashSet::new();
+        let mut normalized = Vec::with_capacity(addresses.len());
+        for address in &addresses {
+            let value = match normalize_address(address.kind, &address.value) {
+                Ok(value) => value,
+                Err(_) => {
+                    eprintln!("Participant {id} has an invalid {:?} address: {}", address.kind, address.value);
+                    address.value.clone()
+                }
+            };
+            let address = ParticipantAddress { kind: address.kind, value };
+            if seen.insert(address.clone()) {
+                owners.entry(address.clone()).or_default().push(id.clone());
+                normalized.push(address);
+            }
+        }
+
+        if normalized != addresses {
+            if !dry_run {
+                part_coll
+                    .update_one(
+                        doc! { "_id": id },
+                        doc! { "$set": { "addresses": bson::to_bson(&normalized)? } },
+                    )
+                    .await?;
+            }
+            updated += 1;
+        }
+    }
+
+    for (address, ids) in owners.iter().filter(|(_, ids)| ids.len() > 1) {
+        eprintln!("Address {} is shared by participants {:?}, consider merging them", address.value, ids);
+    }
+
+    Ok(updated)
+}
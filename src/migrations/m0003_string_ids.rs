@@ -0,0 +1,145 @@
+//! Converts ids stored as BSON binary into hyphenated strings.
+//!
+//! Before ids had their own types, a `Uuid` written through `insert_one` was
+//! stored as 16 bytes of binary while every query compared against strings,
+//! so those documents could not be found by id. Any 16-byte binary value is
+//! an id written that way; nothing else in these collections is binary.
+
+use bson::{doc, spec::BinarySubtype, Bson, Document};
+use futures::{future::BoxFuture, FutureExt, TryStreamExt};
+use mongodb::Database;
+use uuid::Uuid;
+
+const COLLECTIONS: &[&str] = &[
+    "participants",
+    "participant_redirects",
+    "participant_merges",
+    "ai_config_versions",
+    "conversations",
+    "messages",
+    "message_summaries",
+    "agent_jobs",
+    "events",
+    "webhooks",
+    "webhook_deliveries",
+];
+
+pub fn run(db: &Database, dry_run: bool) -> BoxFuture<'_, mongodb::error::Result<u64>> {
+    convert_all(db, dry_run).boxed()
+}
+
+async fn convert_all(db: &Database, dry_run: bool) -> mongodb::error::Result<u64> {
+    let mut updated = 0;
+    for collection in COLLECTIONS {
+        updated += convert_collection(db, collection, dry_run).await?;
+    }
+    Ok(updated)
+}
+
+async fn convert_collection(db: &Database, collection: &str, dry_run: bool) -> mongodb::error::Result<u64> {
+    let coll = db.collection::<Document>(collection);
+    let mut updated = 0;
+
+    let mut cursor = coll.find(doc! {}).await?;
+    while let Some(original) = cursor.try_next().await? {
+        let mut converted = Bson::Document(original.clone());
+        if !convert(&mut converted) {
+            continue;
+        }
+        updated += 1;
+        if dry_run {
+            continue;
+        }
+        let Bson::Document(converted) = converted else { unreachable!("documents stay documents") };
+
+        let old_id = original.get("_id").cloned().unwrap_or(Bson::Null);
+        if converted.get("_id") == Some(&old_id) {
+            coll.replace_one(doc! { "_id": old_id }, converted).await?;
+        } else {
+            super::move_document(db, collection, old_id, converted).await?;
+        }
+    }
+
+    Ok(updated)
+}
+
+/// Rewrites binary ids in place, returns whether anything changed.
+fn convert(value: &mut Bson) -> bool {
+    match value {
+        Bson::Binary(binary)
+            if matches!(binary.subtype, BinarySubtype::Generic | BinarySubtype::Uuid) && binary.bytes.len() == 16 =>
+        {
+            let Ok(uuid) = Uuid::from_slice(&binary.bytes) else { return false };
+            *value = Bson::String(uuid.hyphenated().to_string());
+            true
+        }
+        Bson::Document(doc) => doc.iter_mut().fold(false, |changed, (_, v)| convert(v) | changed),
+        Bson::Array(items) => items.iter_mut().fold(false, |changed, v| convert(v) | changed),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::Binary;
+
+    use super::*;
+
+    const ID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+    fn binary(subtype: BinarySubtype, bytes: Vec<u8>) -> Bson {
+        Bson::Binary(Binary { subtype, bytes })
+    }
+
+    fn uuid_bytes() -> Vec<u8> {
+        Uuid::parse_str(ID).unwrap().as_bytes().to_vec()
+    }
+
+    #[test]
+    fn converts_16_byte_ids_of_both_subtypes() {
+        for subtype in [BinarySubtype::Generic, BinarySubtype::Uuid] {
+            let mut value = binary(subtype, uuid_bytes());
+            assert!(convert(&mut value));
+            assert_eq!(value, Bson::String(ID.to_string()));
+        }
+    }
+
+    #[test]
+    fn leaves_other_values_alone() {
+        let cases = [
+            binary(BinarySubtype::Generic, vec![1, 2, 3]),
+            binary(BinarySubtype::UserDefined(0x80), uuid_bytes()),
+            Bson::String(ID.to_string()),
+            Bson::Int64(16),
+            Bson::Null,
+        ];
+        for original in cases {
+            let mut value = original.clone();
+            assert!(!convert(&mut value), "{original:?}");
+            assert_eq!(value, original);
+        }
+    }
+
+    #[test]
+    fn converts_nested_documents_and_arrays() {
+        let mut value = Bson::Document(doc! {
+            "_id": binary(BinarySubtype::Uuid, uuid_bytes()),
+            "topic": "kept",
+            "participants": [{ "participant_id": binary(BinarySubtype::Generic, uuid_bytes()), "role": "member" }],
+            "participant_ids": [binary(BinarySubtype::Uuid, uuid_bytes()), ID],
+        });
+        assert!(convert(&mut value));
+        assert_eq!(value, Bson::Document(doc! {
+            "_id": ID,
+            "topic": "kept",
+            "participants": [{ "participant_id": ID, "role": "member" }],
+            "participant_ids": [ID, ID],
+        }));
+    }
+
+    #[test]
+    fn reports_no_change_for_converted_documents() {
+        let mut value = Bson::Document(doc! { "_id": ID, "participant_ids": [ID] });
+        assert!(!convert(&mut value));
+    }
+}
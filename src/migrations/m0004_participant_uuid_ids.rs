@@ -0,0 +1,134 @@
+//! Gives participants whose id is not a UUID a new one.
+//!
+//! Participant ids used to be free-form strings while everything referencing
+//! them expected UUIDs. Ids of live participants and of redirects left behind
+//! by merges get a fresh UUID, and every reference is rewritten. The mapping
+//! is kept in `participant_id_mapping`, since clients holding an old id have
+//! to translate it. It is stored before anything is rewritten, so a run that
+//! fails halfway is resumed with the same ids.
+
+use std::collections::BTreeMap;
+
+use bson::{doc, Bson, DateTime as BsonDateTime, Document};
+use futures::{future::BoxFuture, FutureExt, TryStreamExt};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::ids::ParticipantId;
+
+const MAPPING: &str = "participant_id_mapping";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MappedId {
+    #[serde(rename = "_id")]
+    old: String,
+    new: String,
+    /// Set once every reference to `old` has been rewritten.
+    applied_at: Option<BsonDateTime>,
+}
+
+pub fn run(db: &Database, dry_run: bool) -> BoxFuture<'_, mongodb::error::Result<u64>> {
+    reassign(db, dry_run).boxed()
+}
+
+async fn legacy_ids(coll: &Collection<Document>) -> mongodb::error::Result<Vec<String>> {
+    let mut ids = Vec::new();
+    let mut cursor = coll.find(doc! {}).projection(doc! { "_id": 1 }).await?;
+    while let Some(d) = cursor.try_next().await? {
+        if let Ok(id) = d.get_str("_id") {
+            if Uuid::parse_str(id).is_err() {
+                ids.push(id.to_string());
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// Moves a document to its new `_id`, if it has not been moved yet.
+async fn move_document(db: &Database, collection: &str, old: &str, new: &str) -> mongodb::error::Result<()> {
+    let coll = db.collection::<Document>(collection);
+    if let Some(mut d) = coll.find_one(doc! { "_id": old }).await? {
+        d.insert("_id", new);
+        super::move_document(db, collection, Bson::String(old.to_string()), d).await?;
+    }
+    Ok(())
+}
+
+async fn reassign(db: &Database, dry_run: bool) -> mongodb::error::Result<u64> {
+    let participants = db.collection::<Document>("participants");
+    let redirects = db.collection::<Document>("participant_redirects");
+    let mapping_coll = db.collection::<MappedId>(MAPPING);
+
+    // Ids handed out by an earlier, interrupted run are reused.
+    let mut mapping: BTreeMap<String, MappedId> = BTreeMap::new();
+    let mut cursor = mapping_coll.find(doc! {}).await?;
+    while let Some(entry) = cursor.try_next().await? {
+        mapping.insert(entry.old.clone(), entry);
+    }
+
+    let mut fresh = Vec::new();
+    for old in legacy_ids(&participants).await?.into_iter().chain(legacy_ids(&redirects).await?) {
+        if mapping.contains_key(&old) {
+            continue;
+        }
+        let entry = MappedId { old: old.clone(), new: ParticipantId::generate().to_string(), applied_at: None };
+        fresh.push(entry.clone());
+        mapping.insert(old, entry);
+    }
+
+    let pending: Vec<&MappedId> = mapping.values().filter(|entry| entry.applied_at.is_none()).collect();
+    if dry_run || pending.is_empty() {
+        return Ok(pending.len() as u64);
+    }
+
+    if !fresh.is_empty() {
+        mapping_coll.insert_many(&fresh).await?;
+    }
+
+    let conversations = db.collection::<Document>("conversations");
+
+    for MappedId { old, new, .. } in pending.iter().copied() {
+        move_document(db, "participants", old, new).await?;
+        move_document(db, "participant_redirects", old, new).await?;
+
+        // Plain references.
+        for (collection, field) in [
+            ("messages", "sender_id"),
+            ("ai_config_versions", "participant_id"),
+            ("agent_jobs", "participant_id"),
+            ("participant_redirects", "target_id"),
+            ("participant_merges", "target_id"),
+            ("participant_merges", "source_id"),
+            ("participant_merges", "source_snapshot._id"),
+        ] {
+            db.collection::<Document>(collection)
+                .update_many(doc! { field: old }, doc! { "$set": { field: new } })
+                .await?;
+        }
+
+        conversations
+            .update_many(
+                doc! { "participants.participant_id": old },
+                doc! { "$set": { "participants.$[entry].participant_id": new } },
+            )
+            .array_filters(vec![doc! { "entry.participant_id": old }])
+            .await?;
+
+        // Per-participant streams filter the log on these.
+        db.collection::<Document>("events")
+            .update_many(
+                doc! { "participant_ids": old },
+                doc! { "$set": { "participant_ids.$[id]": new } },
+            )
+            .array_filters(vec![doc! { "id": old }])
+            .await?;
+
+        mapping_coll
+            .update_one(doc! { "_id": old }, doc! { "$set": { "applied_at": BsonDateTime::now() } })
+            .await?;
+        println!("Participant {old} is now {new}");
+    }
+
+    Ok(pending.len() as u64)
+}
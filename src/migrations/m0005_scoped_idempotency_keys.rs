@@ -0,0 +1,39 @@
+//! Drops the global `Idempotency-Key` index and the unscoped keys it
+//! covered. Keys are now unique per caller and stored under `idempotency`;
+//! the old ones cannot be assigned to a caller, and they only matter for
+//! retries shortly after a request anyway.
+
+use bson::{doc, Document};
+use futures::{future::BoxFuture, FutureExt};
+use mongodb::{error::ErrorKind, Database};
+
+const LEGACY_INDEX: &str = "idempotency_key_unique";
+/// `IndexNotFound` and `NamespaceNotFound`: nothing to drop.
+const NOTHING_TO_DROP: [i32; 2] = [27, 26];
+
+pub fn run(db: &Database, dry_run: bool) -> BoxFuture<'_, mongodb::error::Result<u64>> {
+    unscope(db, dry_run).boxed()
+}
+
+async fn unscope(db: &Database, dry_run: bool) -> mongodb::error::Result<u64> {
+    let msg_coll = db.collection::<Document>("messages");
+    let legacy = doc! { "idempotency_key": { "$exists": true } };
+
+    if dry_run {
+        return msg_coll.count_documents(legacy).await;
+    }
+
+    // Until the index is gone, a key used by one caller blocks it for all.
+    if let Err(e) = msg_coll.drop_index(LEGACY_INDEX).await {
+        match *e.kind {
+            ErrorKind::Command(ref err) if NOTHING_TO_DROP.contains(&err.code) => {}
+            _ => return Err(e),
+        }
+    }
+
+    let result = msg_coll
+        .update_many(legacy, doc! { "$unset": { "idempotency_key": "" } })
+        .await?;
+
+    Ok(result.modified_count)
+}
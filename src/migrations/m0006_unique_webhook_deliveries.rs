@@ -0,0 +1,42 @@
+//! Removes repeated deliveries of an event to the same subscription.
+//!
+//! Dispatchers on several instances used to enqueue the same events, so a
+//! subscription could hold more than one delivery per event. The oldest one
+//! is kept and the rest removed, which lets the unique index on subscription
+//! and event be built.
+
+use bson::{doc, Bson, Document};
+use futures::{future::BoxFuture, FutureExt, TryStreamExt};
+use mongodb::Database;
+
+pub fn run(db: &Database, dry_run: bool) -> BoxFuture<'_, mongodb::error::Result<u64>> {
+    dedupe(db, dry_run).boxed()
+}
+
+async fn dedupe(db: &Database, dry_run: bool) -> mongodb::error::Result<u64> {
+    let coll = db.collection::<Document>("webhook_deliveries");
+    let mut cursor = coll
+        .aggregate(vec![
+            doc! { "$sort": { "created_at": 1, "_id": 1 } },
+            doc! { "$group": {
+                "_id": { "subscription_id": "$subscription_id", "event_id": "$event._id" },
+                "ids": { "$push": "$_id" },
+            } },
+            doc! { "$match": { "ids.1": { "$exists": true } } },
+        ])
+        .await?;
+
+    let mut removed = 0;
+    while let Some(group) = cursor.try_next().await? {
+        let repeated: Vec<Bson> = group.get_array("ids").map(|ids| ids[1..].to_vec()).unwrap_or_default();
+        if dry_run {
+            removed += repeated.len() as u64;
+            continue;
+        }
+        removed += coll
+            .delete_many(doc! { "_id": { "$in": repeated } })
+            .await?
+            .deleted_count;
+    }
+    Ok(removed)
+}
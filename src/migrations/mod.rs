@@ -0,0 +1,455 @@
+//! Versioned data migrations.
+//!
+//! Every migration runs once, in version order, and is recorded in
+//! `schema_migrations` together with a checksum of its source file. A recorded
+//! migration whose source has since changed is refused rather than silently
+//! skipped, since the data was shaped by the old code. Runners take a lock so
+//! that several instances starting at once do not migrate concurrently.
+
+mod m0001_participant_addresses;
+mod m0002_normalize_participant_addresses;
+mod m0003_string_ids;
+mod m0004_participant_uuid_ids;
+mod m0005_scoped_idempotency_keys;
+mod m0006_unique_webhook_deliveries;
+
+use std::fmt;
+use std::time::Instant;
+
+use bson::{doc, Bson, Document};
+use chrono::{DateTime, Utc};
+use futures::{future::BoxFuture, TryStreamExt};
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::indexes::is_duplicate_key;
+
+/// A lock older than this is assumed to belong to a runner that died.
+const LOCK_STALE_SECS: i64 = 15 * 60;
+/// Journal of `_id` changes in progress, see [`move_document`].
+const PENDING_MOVES: &str = "migration_moves";
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    source: &'static str,
+    /// Checksums of earlier revisions of the source that did the same thing,
+    /// e.g. after a model change forced an edit. Only ever append to this.
+    former_checksums: &'static [&'static str],
+    /// Applies the migration and returns the number of affected documents.
+    /// In a dry run nothing is written and the documents that would be
+    /// affected are counted instead.
+    run: for<'a> fn(&'a Database, bool) -> BoxFuture<'a, mongodb::error::Result<u64>>,
+}
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        hex::encode(Sha256::digest(self.source.as_bytes()))
+    }
+
+    fn accepts(&self, checksum: &str) -> bool {
+        self.checksum() == checksum || self.former_checksums.contains(&checksum)
+    }
+}
+
+pub fn all() -> Vec<Migration> {
+    vec![
+        Migration {
+            version: 1,
+            name: "participant_addresses",
+            source: include_str!("m0001_participant_addresses.rs"),
+            former_checksums: &[],
+            run: m0001_participant_addresses::run,
+        },
+        Migration {
+            version: 2,
+            name: "normalize_participant_addresses",
+            source: include_str!("m0002_normalize_participant_addresses.rs"),
+            former_checksums: &["fb1582a42136d2381e31cc379a2821775552094129ec20ab8afc78e179f66b5d"],
+            run: m0002_normalize_participant_addresses::run,
+        },
+        Migration {
+            version: 3,
+            name: "string_ids",
+            source: include_str!("m0003_string_ids.rs"),
+            former_checksums: &["f017be2dcc46437da493911d9a300be5945d7927382a5e13adbfea09aa34905e"],
+            run: m0003_string_ids::run,
+        },
+        Migration {
+            version: 4,
+            name: "participant_uuid_ids",
+            source: include_str!("m0004_participant_uuid_ids.rs"),
+            former_checksums: &["985338caa0325afbd5f51d7f332fa4674b59c63c993e6d80eca3af73fcbe7a06"],
+            run: m0004_participant_uuid_ids::run,
+        },
+        Migration {
+            version: 5,
+            name: "scoped_idempotency_keys",
+            source: include_str!("m0005_scoped_idempotency_keys.rs"),
+            former_checksums: &[],
+            run: m0005_scoped_idempotency_keys::run,
+        },
+        Migration {
+            version: 6,
+            name: "unique_webhook_deliveries",
+            source: include_str!("m0006_unique_webhook_deliveries.rs"),
+            former_checksums: &[],
+            run: m0006_unique_webhook_deliveries::run,
+        },
+    ]
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppliedMigration {
+    #[serde(rename = "_id")]
+    version: u32,
+    name: String,
+    checksum: String,
+    applied_at: DateTime<Utc>,
+    duration_ms: u64,
+    affected: u64,
+}
+
+#[derive(Debug)]
+pub enum MigrationStatus {
+    /// Recorded by an earlier run.
+    Applied,
+    Ran { affected: u64, duration_ms: u64 },
+    /// Would run, only reported in dry runs.
+    Pending { affected: u64 },
+}
+
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub migrations: Vec<(u32, &'static str, MigrationStatus)>,
+    /// Versions recorded in the database that this build does not know,
+    /// e.g. after a downgrade.
+    pub unknown: Vec<(u32, String)>,
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Database(mongodb::error::Error),
+    /// Another runner holds the lock, or took it over from this one.
+    Locked,
+    ChecksumMismatch { version: u32, name: String },
+    /// A migration failed, the ones before it stay applied.
+    Failed { version: u32, name: &'static str, source: mongodb::error::Error },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Database(e) => write!(f, "{e}"),
+            MigrationError::Locked => write!(f, "another instance is running migrations"),
+            MigrationError::ChecksumMismatch { version, name } => {
+                write!(f, "migration {version} ({name}) was changed after it had been applied")
+            }
+            MigrationError::Failed { version, name, source } => {
+                write!(f, "migration {version} ({name}) failed: {source}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<mongodb::error::Error> for MigrationError {
+    fn from(e: mongodb::error::Error) -> Self {
+        MigrationError::Database(e)
+    }
+}
+
+/// Takes the lock, returns the token identifying this runner as its owner.
+async fn acquire_lock(db: &Database) -> Result<String, MigrationError> {
+    let owner = Uuid::new_v4().to_string();
+    let stale = Utc::now().timestamp_millis() - LOCK_STALE_SECS * 1000;
+    // A live lock does not match the filter, so the upsert tries to insert a
+    // second document with the same id and is rejected.
+    let result = db
+        .collection::<bson::Document>("migration_lock")
+        .update_one(
+            doc! { "_id": "migrations", "locked_at": { "$lt": bson::DateTime::from_millis(stale) } },
+            doc! { "$set": { "locked_at": bson::DateTime::now(), "owner": &owner } },
+        )
+        .upsert(true)
+        .await;
+    match result {
+        Ok(_) => Ok(owner),
+        Err(e) if is_duplicate_key(&e) => Err(MigrationError::Locked),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Keeps a lock that is still ours from going stale. Fails with
+/// [`MigrationError::Locked`] when another runner has taken it over.
+async fn refresh_lock(db: &Database, owner: &str) -> Result<(), MigrationError> {
+    let result = db
+        .collection::<bson::Document>("migration_lock")
+        .update_one(
+            doc! { "_id": "migrations", "owner": owner },
+            doc! { "$set": { "locked_at": bson::DateTime::now() } },
+        )
+        .await?;
+    if result.matched_count == 0 {
+        return Err(MigrationError::Locked);
+    }
+    Ok(())
+}
+
+/// Leaves a lock taken over by another runner in place.
+async fn release_lock(db: &Database, owner: &str) {
+    if let Err(e) = db
+        .collection::<bson::Document>("migration_lock")
+        .delete_one(doc! { "_id": "migrations", "owner": owner })
+        .await
+    {
+        eprintln!("Failed to release the migration lock: {e}");
+    }
+}
+
+/// Gives a document of `collection` a new `_id`, which cannot be changed in
+/// place. The original is deleted before the copy is inserted, otherwise the
+/// two would collide on the collection's unique indexes. The copy is
+/// journaled first, so a run that dies in between loses nothing:
+/// [`finish_moves`] completes the move on the next run.
+async fn move_document(
+    db: &Database,
+    collection: &str,
+    old_id: Bson,
+    moved: Document,
+) -> mongodb::error::Result<()> {
+    let entry = doc! { "collection": collection, "old_id": old_id, "document": moved };
+    let journaled = db.collection::<Document>(PENDING_MOVES)
+        .insert_one(&entry)
+        .await?;
+    complete_move(db, journaled.inserted_id, entry).await
+}
+
+async fn complete_move(db: &Database, journal_id: Bson, entry: Document) -> mongodb::error::Result<()> {
+    let corrupt = || mongodb::error::Error::custom(format!("malformed entry {journal_id} in {PENDING_MOVES}"));
+    let collection = entry.get_str("collection").map_err(|_| corrupt())?;
+    let old_id = entry.get("old_id").cloned().ok_or_else(corrupt)?;
+    let moved = entry.get_document("document").map_err(|_| corrupt())?;
+    let new_id = moved.get("_id").cloned().ok_or_else(corrupt)?;
+
+    let coll = db.collection::<Document>(collection);
+    coll.delete_one(doc! { "_id": old_id }).await?;
+    // Replacing makes a repeated completion harmless.
+    coll.replace_one(doc! { "_id": new_id }, moved.clone())
+        .upsert(true)
+        .await?;
+    db.collection::<Document>(PENDING_MOVES)
+        .delete_one(doc! { "_id": journal_id })
+        .await?;
+    Ok(())
+}
+
+/// Completes the moves an earlier run was interrupted in.
+async fn finish_moves(db: &Database) -> mongodb::error::Result<()> {
+    let mut cursor = db.collection::<Document>(PENDING_MOVES).find(doc! {}).await?;
+    while let Some(entry) = cursor.try_next().await? {
+        let journal_id = entry.get("_id").cloned().unwrap_or(Bson::Null);
+        complete_move(db, journal_id, entry).await?;
+    }
+    Ok(())
+}
+
+/// Runs pending migrations in order and stops at the first failure. A dry
+/// run takes no lock and writes nothing.
+pub async fn run(db: &Database, dry_run: bool) -> Result<MigrationReport, MigrationError> {
+    if dry_run {
+        return run_pending(db, None).await;
+    }
+    let owner = acquire_lock(db).await?;
+    let result = run_pending(db, Some(&owner)).await;
+    release_lock(db, &owner).await;
+    result
+}
+
+/// `lock` is the owner token of the lock held for a real run, `None` for a
+/// dry run.
+async fn run_pending(db: &Database, lock: Option<&str>) -> Result<MigrationReport, MigrationError> {
+    let dry_run = lock.is_none();
+    let coll = db.collection::<AppliedMigration>("schema_migrations");
+    let applied: Vec<AppliedMigration> = coll.find(doc! {}).await?.try_collect().await?;
+    let migrations = all();
+    let mut report = MigrationReport::default();
+
+    for record in &applied {
+        match migrations.iter().find(|m| m.version == record.version) {
+            Some(m) if !m.accepts(&record.checksum) => {
+                return Err(MigrationError::ChecksumMismatch { version: m.version, name: m.name.to_string() });
+            }
+            Some(_) => {}
+            None => report.unknown.push((record.version, record.name.clone())),
+        }
+    }
+
+    if !dry_run {
+        finish_moves(db).await?;
+    }
+
+    for migration in &migrations {
+        if applied.iter().any(|r| r.version == migration.version) {
+            report.migrations.push((migration.version, migration.name, MigrationStatus::Applied));
+            continue;
+        }
+
+        // A long run must not look like a dead one to other runners.
+        if let Some(owner) = lock {
+            refresh_lock(db, owner).await?;
+        }
+
+        let started = Instant::now();
+        let affected = (migration.run)(db, dry_run)
+            .await
+            .map_err(|source| MigrationError::Failed { version: migration.version, name: migration.name, source })?;
+
+        if dry_run {
+            report.migrations.push((migration.version, migration.name, MigrationStatus::Pending { affected }));
+            continue;
+        }
+
+        let duration_ms = started.elapsed().as_millis() as u64;
+        coll.insert_one(AppliedMigration {
+            version: migration.version,
+            name: migration.name.to_string(),
+            checksum: migration.checksum(),
+            applied_at: Utc::now(),
+            duration_ms,
+            affected,
+        })
+            .await?;
+        report.migrations.push((migration.version, migration.name, MigrationStatus::Ran { affected, duration_ms }));
+    }
+
+    Ok(report)
+}
+
+pub fn print_report(report: &MigrationReport) {
+    for (version, name, status) in &report.migrations {
+        match status {
+            MigrationStatus::Applied => {}
+            MigrationStatus::Ran { affected, duration_ms } => {
+                println!("Applied migration {version} ({name}): {affected} documents in {duration_ms} ms");
+            }
+            MigrationStatus::Pending { affected } => {
+                println!("Pending migration {version} ({name}) would affect {affected} documents");
+            }
+        }
+    }
+    for (version, name) in &report.unknown {
+        eprintln!("Migration {version} ({name}) is recorded but unknown to this build");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bson::{spec::BinarySubtype, Binary};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::indexes::{self, IndexStatus};
+
+    fn binary_id(id: Uuid) -> Bson {
+        Bson::Binary(Binary { subtype: BinarySubtype::Uuid, bytes: id.as_bytes().to_vec() })
+    }
+
+    /// Runs every migration against a throwaway database that already has
+    /// the declared unique indexes. Needs a server in
+    /// `MARATUS_TEST_MONGODB_URI` and is skipped without one.
+    #[tokio::test]
+    async fn id_migrations_pass_the_unique_indexes() {
+        let Ok(uri) = std::env::var("MARATUS_TEST_MONGODB_URI") else {
+            eprintln!("MARATUS_TEST_MONGODB_URI is not set, skipping");
+            return;
+        };
+        let client = mongodb::Client::with_uri_str(&uri).await.unwrap();
+        let db = client.database(&format!("maratus_test_{}", Uuid::new_v4().simple()));
+
+        let outcome = migrate_seeded(&db).await;
+        db.drop().await.unwrap();
+        outcome.unwrap();
+    }
+
+    async fn migrate_seeded(db: &Database) -> Result<(), Box<dyn std::error::Error>> {
+        let report = indexes::sync(db, false).await?;
+        assert!(report.indexes.iter().all(|(_, _, status)| matches!(status, IndexStatus::Created)));
+
+        let binary_participant = Uuid::new_v4();
+        let conversation = Uuid::new_v4();
+        let message = Uuid::new_v4();
+        let participants = db.collection::<Document>("participants");
+        participants
+            .insert_many([
+                doc! {
+                    "_id": binary_id(binary_participant),
+                    "type": "human",
+                    "addresses": [{ "kind": "email", "value": "ada@example.com" }]
+                },
+                doc! {
+                    "_id": "alice",
+                    "type": "human",
+                    "addresses": [{ "kind": "phone", "value": "+15550100" }]
+                },
+            ])
+            .await?;
+        db.collection::<Document>("conversations")
+            .insert_one(doc! {
+                "_id": binary_id(conversation),
+                "external_id": Uuid::new_v4().to_string(),
+                "participants": [
+                    { "participant_id": binary_id(binary_participant), "role": "member" },
+                    { "participant_id": "alice", "role": "member" }
+                ]
+            })
+            .await?;
+        db.collection::<Document>("messages")
+            .insert_one(doc! {
+                "_id": binary_id(message),
+                "conversation_id": binary_id(conversation),
+                "sender_id": "alice",
+                "channel": "sms",
+                "external_id": "m-1"
+            })
+            .await?;
+
+        run(db, false).await?;
+
+        let binary = doc! { "_id": { "$type": "binData" } };
+        for collection in ["participants", "conversations", "messages"] {
+            assert_eq!(db.collection::<Document>(collection).count_documents(binary.clone()).await?, 0);
+        }
+        assert_eq!(participants.count_documents(doc! {}).await?, 2);
+        assert!(participants.find_one(doc! { "_id": binary_participant.to_string() }).await?.is_some());
+
+        let mapped = db.collection::<Document>("participant_id_mapping")
+            .find_one(doc! { "_id": "alice" })
+            .await?
+            .expect("mapping recorded");
+        assert!(mapped.get_datetime("applied_at").is_ok());
+        let alice = mapped.get_str("new")?;
+        let moved = participants.find_one(doc! { "_id": alice }).await?.expect("alice moved");
+        assert_eq!(moved.get_array("addresses")?.len(), 1);
+
+        let message = db.collection::<Document>("messages")
+            .find_one(doc! { "_id": message.to_string() })
+            .await?
+            .expect("message moved");
+        assert_eq!(message.get_str("sender_id")?, alice);
+        assert_eq!(message.get_str("conversation_id")?, conversation.to_string());
+
+        let members = db.collection::<Document>("conversations")
+            .count_documents(doc! {
+                "_id": conversation.to_string(),
+                "participants.participant_id": { "$all": [binary_participant.to_string(), alice] }
+            })
+            .await?;
+        assert_eq!(members, 1);
+        assert_eq!(db.collection::<Document>(PENDING_MOVES).count_documents(doc! {}).await?, 0);
+        Ok(())
+    }
+}
@@ -1,6 +1,15 @@
-use bson::DateTime as BsonDateTime;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use bson::{DateTime as BsonDateTime, Document};
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
+
+use crate::events::{Event, EventKind};
+use crate::ids::{
+    AgentJobId, AiConfigVersionId, ApiKeyId, ConversationId, MessageId, MessageSummaryId, ParticipantId, ParticipantMergeId,
+    WebhookDeliveryId, WebhookId,
+};
 
 // ___ participants collection ___
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -10,62 +19,467 @@ pub enum ParticipantType {
     Ai,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressKind {
+    Email,
+    Phone,
+    Slack,
+    Discord,
+    Other,
+}
+
+impl AddressKind {
+    /// Address kind a sender is identified by on the given message channel.
+    pub fn for_channel(channel: &str) -> Option<Self> {
+        match channel.to_ascii_lowercase().as_str() {
+            "email" => Some(AddressKind::Email),
+            "sms" | "mms" | "whatsapp" | "phone" | "voice" => Some(AddressKind::Phone),
+            "slack" => Some(AddressKind::Slack),
+            "discord" => Some(AddressKind::Discord),
+            _ => None,
+        }
+    }
+
+    /// Best guess for addresses submitted without a kind.
+    pub fn infer(value: &str) -> Self {
+        let phone_like = value.chars().any(|c| c.is_ascii_digit())
+            && value
+                .trim_start_matches('+')
+                .chars()
+                .all(|c| c.is_ascii_digit() || " ()-.".contains(c));
+        if value.contains('@') {
+            AddressKind::Email
+        } else if phone_like {
+            AddressKind::Phone
+        } else {
+            AddressKind::Other
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct ParticipantAddress {
+    pub kind: AddressKind,
+    pub value: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Participant {
     #[serde(rename = "_id")]
-    pub id: String,
-    pub address: String,
+    pub id: ParticipantId,
+    #[serde(default)]
+    pub addresses: Vec<ParticipantAddress>,
     pub display_name: Option<String>,
     #[serde(rename = "type")]
     pub participant_type: ParticipantType,
     pub description: Option<String>,
+    #[serde(default)]
+    pub ai_config: Option<AiConfig>,
+}
+
+// ___ embedded in Participant.ai_config for AI participants ___
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AiConfig {
+    pub version: u32,
+    pub provider: String,
+    pub model: String,
+    pub system_prompt: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_context_tokens: Option<u32>,
+    #[serde(default)]
+    pub tools: Vec<String>,
+    pub updated_at: BsonDateTime,
+}
+
+// ___ ai_config_versions collection (every config an AI participant had) ___
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AiConfigVersion {
+    #[serde(rename = "_id")]
+    pub id: AiConfigVersionId,
+    pub participant_id: ParticipantId,
+    pub config: AiConfig,
+}
+
+// ___ participant_redirects collection (ids of participants merged away) ___
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ParticipantRedirect {
+    #[serde(rename = "_id")]
+    pub id: ParticipantId,
+    pub target_id: ParticipantId,
+    pub merged_at: BsonDateTime,
+}
+
+// ___ participant_merges collection (audit trail of merges) ___
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ParticipantMerge {
+    #[serde(rename = "_id")]
+    pub id: ParticipantMergeId,
+    pub target_id: ParticipantId,
+    pub source_id: ParticipantId,
+    pub source_snapshot: Participant,
+    pub messages_updated: u64,
+    pub conversations_updated: u64,
+    pub merged_at: BsonDateTime,
 }
 
 // ___ embedded in Conversation.participants ___
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConvRole {
+    Owner,
+    #[default]
+    Member,
+    Observer,
+    Bot,
+}
+
+impl ConvRole {
+    /// Role given to a participant joining without an explicit one.
+    pub fn default_for(participant_type: &ParticipantType) -> Self {
+        match participant_type {
+            ParticipantType::Human => ConvRole::Member,
+            ParticipantType::Ai => ConvRole::Bot,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ConvParticipant {
-    pub participant_id: Uuid,
+    pub participant_id: ParticipantId,
+    #[serde(default)]
+    pub role: ConvRole,
     pub joined_at: BsonDateTime,
+    #[serde(default)]
+    pub left_at: Option<BsonDateTime>,
+    #[serde(default)]
+    pub last_read_at: Option<BsonDateTime>,
 }
 
 // ___ conversations collection ___
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Conversation {
     #[serde(rename = "_id")]
-    pub id: Uuid,
+    pub id: ConversationId,
     pub external_id: String,
     pub topic: Option<String>,
     pub started_at: BsonDateTime,
+    #[serde(default)]
+    pub last_message_at: Option<BsonDateTime>,
+    #[serde(default)]
+    pub last_message_id: Option<MessageId>,
+    #[serde(default)]
+    pub message_count: i64,
+    #[serde(default)]
+    pub channel_counts: HashMap<String, i64>,
     pub participants: Vec<ConvParticipant>,
+    #[serde(default)]
+    pub allow_departed_senders: bool,
     pub summary: Option<String>,
     pub context: Option<String>,
 }
 
+impl Conversation {
+    pub fn membership(&self, participant_id: &ParticipantId) -> Option<&ConvParticipant> {
+        self.participants.iter().find(|cp| &cp.participant_id == participant_id)
+    }
+}
+
 // ___ messages collection ___
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Message {
     #[serde(rename = "_id")]
-    pub id: Uuid,
-    pub conversation_id: Uuid,
-    pub sender_id: Uuid,
+    pub id: MessageId,
+    pub conversation_id: ConversationId,
+    pub sender_id: ParticipantId,
     pub channel: String,
     pub external_id: Option<String>,
     pub sent_at: BsonDateTime,
     pub content: String,
     pub summary: Option<String>,
     pub context: Option<String>,
+    /// Client-supplied `Idempotency-Key` for messages without an external id.
+    /// It names the caller, so it is read from the database but never
+    /// serialized into responses, events or webhooks; [`Message::to_stored`]
+    /// writes it.
+    #[serde(default, skip_serializing)]
+    pub idempotency: Option<IdempotencyKey>,
+}
+
+impl Message {
+    /// The document to insert, including the idempotency key.
+    pub fn to_stored(&self) -> Result<Document, bson::ser::Error> {
+        let mut stored = bson::to_document(self)?;
+        if let Some(idempotency) = &self.idempotency {
+            stored.insert("idempotency", bson::to_bson(idempotency)?);
+        }
+        Ok(stored)
+    }
+}
+
+/// An `Idempotency-Key` is only unique per caller, and only ever stands for
+/// the request it was first used with.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct IdempotencyKey {
+    /// The API key or participant that sent the request.
+    pub scope: String,
+    pub key: String,
+    /// Hex SHA-256 of the request body.
+    pub request_hash: String,
 }
 
 // ___ summaries collection (for storing summarized message ranges) ___
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MessageSummary {
     #[serde(rename = "_id")]
-    pub id: Uuid,
-    pub conversation_id: Uuid,
-    pub message_ids: Vec<Uuid>,
+    pub id: MessageSummaryId,
+    pub conversation_id: ConversationId,
+    pub message_ids: Vec<MessageId>,
     pub summary: String,
     pub context: Option<String>,
     pub created_at: BsonDateTime,
     pub from_date: BsonDateTime,
     pub to_date: BsonDateTime,
-}
\ No newline at end of file
+}
+
+// ___ agent_jobs collection (reply work for AI participants) ___
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AgentJobStatus {
+    Pending,
+    Claimed,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AgentJob {
+    #[serde(rename = "_id")]
+    pub id: AgentJobId,
+    pub participant_id: ParticipantId,
+    pub conversation_id: ConversationId,
+    pub trigger_message_id: MessageId,
+    pub status: AgentJobStatus,
+    pub attempts: u32,
+    pub max_attempts: u32,
+    pub worker_id: Option<String>,
+    pub lease_expires_at: Option<BsonDateTime>,
+    pub last_error: Option<String>,
+    pub result_message_id: Option<MessageId>,
+    pub created_at: BsonDateTime,
+    pub updated_at: BsonDateTime,
+}
+
+// ___ webhooks collection (outbound event subscriptions) ___
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookSubscription {
+    #[serde(rename = "_id")]
+    pub id: WebhookId,
+    pub url: String,
+    /// Key for the `X-Maratus-Signature` HMAC, only shown when created.
+    /// Stored in plaintext since signing needs the raw value.
+    pub secret: String,
+    /// Empty lists match everything.
+    #[serde(default)]
+    pub event_types: Vec<EventKind>,
+    #[serde(default)]
+    pub conversation_ids: Vec<ConversationId>,
+    /// Matches events whose payload has a `channel`, i.e. message events.
+    #[serde(default)]
+    pub channels: Vec<String>,
+    pub created_at: BsonDateTime,
+}
+
+// ___ webhook_deliveries collection (one per subscription and event) ___
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookDeliveryStatus {
+    Pending,
+    Delivered,
+    Dead,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookAttempt {
+    pub attempted_at: BsonDateTime,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookDelivery {
+    #[serde(rename = "_id")]
+    pub id: WebhookDeliveryId,
+    pub subscription_id: WebhookId,
+    pub url: String,
+    pub event: Event,
+    pub status: WebhookDeliveryStatus,
+    pub attempts: u32,
+    pub next_attempt_at: Option<BsonDateTime>,
+    #[serde(default)]
+    pub attempt_log: Vec<WebhookAttempt>,
+    pub created_at: BsonDateTime,
+    pub updated_at: BsonDateTime,
+}
+
+// ___ api_keys collection (credentials of API clients) ___
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiKey {
+    #[serde(rename = "_id")]
+    pub id: ApiKeyId,
+    pub name: String,
+    /// Start of the key, enough to recognise it in listings.
+    pub prefix: String,
+    /// SHA-256 of the key, the key itself is never stored.
+    pub key_hash: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: BsonDateTime,
+    pub last_used_at: Option<BsonDateTime>,
+    pub revoked_at: Option<BsonDateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    Participants,
+    Conversations,
+    Messages,
+    AgentJobs,
+    Events,
+    Webhooks,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 6] = [
+        Resource::Participants,
+        Resource::Conversations,
+        Resource::Messages,
+        Resource::AgentJobs,
+        Resource::Events,
+        Resource::Webhooks,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Resource::Participants => "participants",
+            Resource::Conversations => "conversations",
+            Resource::Messages => "messages",
+            Resource::AgentJobs => "agent_jobs",
+            Resource::Events => "events",
+            Resource::Webhooks => "webhooks",
+        }
+    }
+}
+
+/// A permission of an API key, written `<resource>:read`, `<resource>:write`
+/// or `admin`. Write implies read, admin implies everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Read(Resource),
+    Write(Resource),
+    Admin,
+}
+
+impl Scope {
+    pub fn grants(self, needed: Scope) -> bool {
+        match (self, needed) {
+            (Scope::Admin, _) => true,
+            (Scope::Write(have), Scope::Read(want) | Scope::Write(want)) => have == want,
+            (Scope::Read(have), Scope::Read(want)) => have == want,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Read(resource) => write!(f, "{}:read", resource.as_str()),
+            Scope::Write(resource) => write!(f, "{}:write", resource.as_str()),
+            Scope::Admin => f.write_str("admin"),
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "admin" {
+            return Ok(Scope::Admin);
+        }
+        let invalid = || format!("unknown scope {s:?}, expected <resource>:read, <resource>:write or admin");
+        let (resource, access) = s.split_once(':').ok_or_else(invalid)?;
+        let resource = Resource::ALL.into_iter().find(|r| r.as_str() == resource).ok_or_else(invalid)?;
+        match access {
+            "read" => Ok(Scope::Read(resource)),
+            "write" => Ok(Scope::Write(resource)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Serialize for Scope {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Scope {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = std::borrow::Cow::<str>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idempotency_keys_are_stored_but_never_shown() {
+        let msg = Message {
+            id: MessageId::generate(),
+            conversation_id: ConversationId::generate(),
+            sender_id: ParticipantId::generate(),
+            channel: "chat".to_string(),
+            external_id: None,
+            sent_at: BsonDateTime::now(),
+            content: "hi".to_string(),
+            summary: None,
+            context: None,
+            idempotency: Some(IdempotencyKey {
+                scope: "api_key:a".to_string(),
+                key: "retry-1".to_string(),
+                request_hash: "h1".to_string(),
+            }),
+        };
+        assert!(serde_json::to_value(&msg).unwrap().get("idempotency").is_none());
+        assert!(!bson::to_document(&msg).unwrap().contains_key("idempotency"));
+
+        let stored: Message = bson::from_document(msg.to_stored().unwrap()).unwrap();
+        assert_eq!(stored.idempotency, msg.idempotency);
+    }
+
+    #[test]
+    fn scopes_grant_what_they_imply() {
+        use Resource::*;
+        let cases = [
+            (Scope::Admin, Scope::Admin, true),
+            (Scope::Admin, Scope::Write(Webhooks), true),
+            (Scope::Admin, Scope::Read(Events), true),
+            (Scope::Write(Messages), Scope::Write(Messages), true),
+            (Scope::Write(Messages), Scope::Read(Messages), true),
+            (Scope::Write(Messages), Scope::Write(Conversations), false),
+            (Scope::Write(Messages), Scope::Admin, false),
+            (Scope::Read(Messages), Scope::Read(Messages), true),
+            (Scope::Read(Messages), Scope::Write(Messages), false),
+            (Scope::Read(Messages), Scope::Read(Events), false),
+            (Scope::Read(Events), Scope::Admin, false),
+        ];
+        for (have, needed, granted) in cases {
+            assert_eq!(have.grants(needed), granted, "{have} for {needed}");
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::fmt;
+
+use crate::models::AddressKind;
+
+#[derive(Debug)]
+pub struct InvalidAddress {
+    pub kind: AddressKind,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvalidAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid {:?} address: {}", self.kind, self.reason)
+    }
+}
+
+/// Canonical form of an address, used both when storing and when looking up,
+/// so differently formatted spellings of one address resolve to one participant.
+pub fn normalize_address(kind: AddressKind, value: &str) -> Result<String, InvalidAddress> {
+    let value = value.trim();
+    let invalid = |reason| InvalidAddress { kind, reason };
+
+    match kind {
+        AddressKind::Email => normalize_email(value).ok_or_else(|| invalid("expected local@domain")),
+        AddressKind::Phone => normalize_phone(value)
+            .ok_or_else(|| invalid("expected an E.164 number with country code")),
+        AddressKind::Slack => normalize_slack(value).ok_or_else(|| invalid("expected a Slack user id")),
+        AddressKind::Discord => normalize_discord(value)
+            .ok_or_else(|| invalid("expected a Discord user id")),
+        AddressKind::Other if value.is_empty() => Err(invalid("address is empty")),
+        AddressKind::Other => Ok(value.to_string()),
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(value: &'a str, prefix: &str) -> &'a str {
+    match value.get(..prefix.len()) {
+        Some(head) if head.eq_ignore_ascii_case(prefix) => &value[prefix.len()..],
+        _ => value,
+    }
+}
+
+/// Folds case on both parts and converts internationalized domains to their
+/// ASCII (punycode) form.
+fn normalize_email(value: &str) -> Option<String> {
+    let value = strip_prefix_ignore_case(value, "mailto:");
+    let (local, domain) = value.rsplit_once('@')?;
+    if local.is_empty() || domain.is_empty() || local.chars().any(char::is_whitespace) {
+        return None;
+    }
+    let domain = idna::domain_to_ascii(domain.trim_end_matches('.')).ok()?;
+    if domain.is_empty() || !domain.contains('.') {
+        return None;
+    }
+    Some(format!("{}@{}", local.to_lowercase(), domain))
+}
+
+/// Strips formatting characters and returns `+<digits>`. A leading `00`
+/// international prefix is accepted in place of `+`.
+fn normalize_phone(value: &str) -> Option<String> {
+    let value = strip_prefix_ignore_case(value, "tel:");
+    let rest = value
+        .strip_prefix('+')
+        .or_else(|| value.strip_prefix("00"))?;
+
+    let mut digits = String::with_capacity(rest.len());
+    for c in rest.chars() {
+        match c {
+            '0'..='9' => digits.push(c),
+            ' ' | '(' | ')' | '-' | '.' => {}
+            _ => return None,
+        }
+    }
+
+    if !(8..=15).contains(&digits.len()) || digits.starts_with('0') {
+        return None;
+    }
+    Some(format!("+{digits}"))
+}
+
+/// Strips mention markup such as `<@U0123ABCD>`.
+fn unwrap_mention(value: &str) -> &str {
+    value
+        .strip_prefix("<@")
+        .and_then(|v| v.strip_suffix('>'))
+        .map(|v| v.trim_start_matches('!'))
+        .unwrap_or(value)
+}
+
+fn normalize_slack(value: &str) -> Option<String> {
+    let id = unwrap_mention(value).to_ascii_uppercase();
+    let valid = (9..=21).contains(&id.len())
+        && matches!(id.chars().next(), Some('U' | 'W'))
+        && id.chars().all(|c| c.is_ascii_alphanumeric());
+    valid.then_some(id)
+}
+
+fn normalize_discord(value: &str) -> Option<String> {
+    let id = unwrap_mention(value);
+    let valid = (17..=20).contains(&id.len()) && id.chars().all(|c| c.is_ascii_digit());
+    valid.then(|| id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(kind: AddressKind, cases: &[(&str, Option<&str>)]) {
+        for (input, expected) in cases {
+            let normalized = normalize_address(kind, input).ok();
+            assert_eq!(normalized.as_deref(), *expected, "{kind:?} {input:?}");
+        }
+    }
+
+    #[test]
+    fn email() {
+        check(AddressKind::Email, &[
+            ("Alice@Example.COM", Some("alice@example.com")),
+            ("  mailto:bob@example.com. ", Some("bob@example.com")),
+            ("MAILTO:x@b\u{fc}cher.de", Some("x@xn--bcher-kva.de")),
+            ("a@b@example.com", Some("a@b@example.com")),
+            ("@example.com", None),
+            ("alice@", None),
+            ("alice@localhost", None),
+            ("al ice@example.com", None),
+            ("alice", None),
+        ]);
+    }
+
+    #[test]
+    fn phone() {
+        check(AddressKind::Phone, &[
+            ("+1 (555) 123-4567", Some("+15551234567")),
+            ("tel:+44.20.7946.0958", Some("+442079460958")),
+            ("0049 30 1234567", Some("+49301234567")),
+            ("5551234567", None),
+            ("+0 555 1234567", None),
+            ("+1234567", None),
+            ("+1234567890123456", None),
+            ("+1 555 CALL NOW", None),
+        ]);
+    }
+
+    #[test]
+    fn slack() {
+        check(AddressKind::Slack, &[
+            ("U0123ABCD", Some("U0123ABCD")),
+            ("<@u0123abcd>", Some("U0123ABCD")),
+            ("W0123ABCDEF", Some("W0123ABCDEF")),
+            ("C0123ABCD", None),
+            ("U012", None),
+            ("U0123-ABCD", None),
+        ]);
+    }
+
+    #[test]
+    fn discord() {
+        check(AddressKind::Discord, &[
+            ("80351110224678912", Some("80351110224678912")),
+            ("<@!80351110224678912>", Some("80351110224678912")),
+            ("8035111022467891", None),
+            ("80351110224678912a", None),
+        ]);
+    }
+
+    #[test]
+    fn other() {
+        check(AddressKind::Other, &[
+            ("  matrix:@alice:example.org ", Some("matrix:@alice:example.org")),
+            ("   ", None),
+        ]);
+    }
+}
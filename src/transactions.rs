@@ -0,0 +1,106 @@
+//! Multi-document transactions where the deployment supports them.
+//!
+//! Transactions need a replica set or a sharded cluster. Writers go through
+//! [`run`], which brackets their writes with a transaction where there is
+//! one; on a standalone server the writer is responsible for undoing the
+//! steps it already applied when a later one fails.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use bson::doc;
+use mongodb::{
+    error::{Error, Result, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT},
+    ClientSession,
+    Database,
+};
+
+use crate::error::{AppError, AppResult};
+
+/// Attempts for a transaction that keeps failing with a transient error,
+/// e.g. a write conflict on the event sequence.
+const MAX_ATTEMPTS: u32 = 10;
+const RETRY_DELAY: Duration = Duration::from_millis(5);
+const MAX_COMMIT_ATTEMPTS: u32 = 3;
+
+static SUPPORTED: OnceLock<bool> = OnceLock::new();
+
+/// Asks the server what it is and remembers whether it can run
+/// transactions. Called once at startup.
+pub async fn detect(db: &Database) -> Result<bool> {
+    let hello = db.run_command(doc! { "hello": 1 }).await?;
+    let replica_set = hello.get_str("setName").is_ok();
+    let sharded = hello.get_str("msg").is_ok_and(|msg| msg == "isdbgrid");
+    let supported = replica_set || sharded;
+    let _ = SUPPORTED.set(supported);
+    Ok(supported)
+}
+
+pub fn supported() -> bool {
+    SUPPORTED.get().copied().unwrap_or(false)
+}
+
+async fn begin(session: &mut ClientSession) -> Result<()> {
+    if supported() {
+        session.start_transaction().await?;
+    }
+    Ok(())
+}
+
+/// Commits, retrying while the outcome of the commit is unknown.
+async fn commit(session: &mut ClientSession) -> Result<()> {
+    if !supported() {
+        return Ok(());
+    }
+    let mut attempt = 1;
+    loop {
+        match session.commit_transaction().await {
+            Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && attempt < MAX_COMMIT_ATTEMPTS => {
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn abort(session: &mut ClientSession) {
+    if supported() {
+        // Fails only when there is nothing left to abort.
+        let _ = session.abort_transaction().await;
+    }
+}
+
+/// Whether the whole transaction may be retried from the start.
+fn is_transient(err: &Error) -> bool {
+    supported() && err.contains_label(TRANSIENT_TRANSACTION_ERROR)
+}
+
+/// Runs `write` in a transaction on a new session, from the start again
+/// while it fails with a transient error. Every write that records an event
+/// conflicts with the others on the event sequence, so such retries are
+/// expected under load. Without transactions `write` runs once and what it
+/// applied before failing stays applied.
+pub async fn run<T>(db: &Database, mut write: impl AsyncFnMut(&mut ClientSession) -> AppResult<T>) -> AppResult<T> {
+    let mut session = db.client()
+        .start_session()
+        .await?;
+
+    let mut attempt = 1;
+    loop {
+        begin(&mut session).await?;
+        let result = match write(&mut session).await {
+            Ok(value) => commit(&mut session).await.map(|()| value).map_err(AppError::from),
+            Err(e) => Err(e),
+        };
+        let Err(e) = result else { return result };
+        abort(&mut session).await;
+
+        if matches!(&e, AppError::Database(err) if is_transient(err)) && attempt < MAX_ATTEMPTS {
+            // Gives the transaction holding the sequence time to commit.
+            tokio::time::sleep(RETRY_DELAY * attempt).await;
+            attempt += 1;
+            continue;
+        }
+        return Err(e);
+    }
+}
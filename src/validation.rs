@@ -0,0 +1,114 @@
+//! Limits and custom rules for request payloads.
+//!
+//! Payloads derive `validator::Validate` and declare their rules on the
+//! fields; handlers call `validate()?` before touching the database. Failures
+//! become `AppError::Validation`, a 422 listing every rejected field.
+
+use chrono::{DateTime, Duration, Utc};
+use validator::{ValidationError, ValidationErrors, ValidationErrorsKind};
+
+use crate::error::{AppError, FieldError};
+
+pub const MAX_CONTENT_CHARS: u64 = 32_768;
+pub const MAX_TEXT_CHARS: u64 = 16_384;
+pub const MAX_NAME_CHARS: u64 = 200;
+pub const MAX_DESCRIPTION_CHARS: u64 = 2_000;
+pub const MAX_TOPIC_CHARS: u64 = 500;
+pub const MAX_EXTERNAL_ID_CHARS: u64 = 255;
+pub const MAX_ADDRESS_CHARS: u64 = 320;
+pub const MAX_SUMMARY_MESSAGES: u64 = 1_000;
+pub const MAX_AI_TOOLS: u64 = 64;
+
+/// Channels messages can be sent on. Lowercase only, since the channel is
+/// part of the deduplication key.
+pub const CHANNELS: &[&str] = &[
+    "chat", "email", "sms", "mms", "whatsapp", "phone", "voice", "slack", "discord",
+];
+
+/// How far `sent_at` may lie ahead of the server clock.
+const MAX_FUTURE_SKEW: Duration = Duration::minutes(5);
+
+pub fn channel(value: &str) -> Result<(), ValidationError> {
+    if CHANNELS.contains(&value) {
+        return Ok(());
+    }
+    Err(ValidationError::new("channel")
+        .with_message(format!("must be one of {}", CHANNELS.join(", ")).into()))
+}
+
+/// Rejects messages from the future beyond clock skew, and timestamps before
+/// the Unix epoch, which only come from broken clients.
+pub fn sent_at(value: &DateTime<Utc>) -> Result<(), ValidationError> {
+    if *value > Utc::now() + MAX_FUTURE_SKEW {
+        return Err(ValidationError::new("skew").with_message(format!(
+            "must be at most {} minutes in the future", MAX_FUTURE_SKEW.num_minutes()
+        ).into()));
+    }
+    if value.timestamp() < 0 {
+        return Err(ValidationError::new("range").with_message("must not be before 1970".into()));
+    }
+    Ok(())
+}
+
+/// Stricter than `length(min = 1)`, which accepts whitespace.
+pub fn not_blank(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(ValidationError::new("blank").with_message("must not be blank".into()));
+    }
+    Ok(())
+}
+
+/// Lists of short identifiers, e.g. the tools of an AI configuration: every
+/// entry is non-blank and at most [`MAX_NAME_CHARS`] long.
+pub fn names(values: &[String]) -> Result<(), ValidationError> {
+    if values.iter().any(|v| v.trim().is_empty() || v.chars().count() as u64 > MAX_NAME_CHARS) {
+        return Err(ValidationError::new("names").with_message(format!(
+            "entries must not be blank and at most {MAX_NAME_CHARS} characters long"
+        ).into()));
+    }
+    Ok(())
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(errors: ValidationErrors) -> Self {
+        let mut fields = Vec::new();
+        collect(&errors, "", &mut fields);
+        fields.sort_by(|a, b| a.field.cmp(&b.field));
+        AppError::Validation(fields)
+    }
+}
+
+fn collect(errors: &ValidationErrors, prefix: &str, out: &mut Vec<FieldError>) {
+    for (field, kind) in errors.errors() {
+        let path = if prefix.is_empty() { field.to_string() } else { format!("{prefix}.{field}") };
+        match kind {
+            ValidationErrorsKind::Field(errs) => out.extend(errs.iter().map(|e| FieldError {
+                field: path.clone(),
+                code: e.code.to_string(),
+                message: message(&path, e),
+            })),
+            ValidationErrorsKind::Struct(nested) => collect(nested, &path, out),
+            ValidationErrorsKind::List(items) => {
+                for (index, nested) in items {
+                    collect(nested, &format!("{path}[{index}]"), out);
+                }
+            }
+        }
+    }
+}
+
+fn message(field: &str, e: &ValidationError) -> String {
+    if let Some(message) = &e.message {
+        return format!("{field} {message}");
+    }
+    let param = |name: &str| e.params.get(name).map(|v| v.to_string());
+    match (e.code.as_ref(), param("min"), param("max")) {
+        ("length", Some(min), Some(max)) => format!("{field} must be between {min} and {max} characters long"),
+        ("length", None, Some(max)) => format!("{field} must be at most {max} characters long"),
+        ("length", Some(min), None) => format!("{field} must be at least {min} characters long"),
+        ("range", Some(min), Some(max)) => format!("{field} must be between {min} and {max}"),
+        ("range", None, Some(max)) => format!("{field} must be at most {max}"),
+        ("range", Some(min), None) => format!("{field} must be at least {min}"),
+        (code, _, _) => format!("{field} is invalid ({code})"),
+    }
+}
@@ -0,0 +1,487 @@
+//! Background delivery of events to webhook subscriptions.
+//!
+//! The dispatcher follows the event log with its own cursor, so events
+//! published while it was down or busy are still delivered. Each matching
+//! subscription gets a delivery record that is retried with exponential
+//! backoff until it succeeds or runs out of attempts and becomes dead.
+//!
+//! Requests are `POST`ed as JSON (the same payload as the event streams) with:
+//!
+//! | header                 | value                                         |
+//! |------------------------|-----------------------------------------------|
+//! | `X-Maratus-Event`      | event type, e.g. `message.created`            |
+//! | `X-Maratus-Delivery`   | delivery id, stable across retries            |
+//! | `X-Maratus-Timestamp`  | unix seconds of this attempt                  |
+//! | `X-Maratus-Signature`  | `sha256=<hex>` HMAC of `{timestamp}.{body}`   |
+//!
+//! Endpoints must be public: hosts resolving to loopback, link-local or
+//! private addresses are refused when a subscription is created and again
+//! on every connection, so a DNS change cannot point deliveries at internal
+//! services. Redirects are not followed.
+//!
+//! Signing needs the raw secret, so secrets are stored in plaintext in the
+//! `webhooks` collection; anyone who can read the database can forge
+//! deliveries.
+
+use std::error::Error as StdError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bson::{doc, DateTime as BsonDateTime};
+use futures::{StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use mongodb::{error::ErrorKind, options::ReturnDocument, Database};
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::events::{Event, EventBus};
+use crate::ids::WebhookDeliveryId;
+use crate::indexes::DUPLICATE_KEY;
+use crate::models::{WebhookAttempt, WebhookDelivery, WebhookDeliveryStatus, WebhookSubscription};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const EVENT_BATCH: i64 = 500;
+const DELIVERY_BATCH: i64 = 50;
+const CONCURRENT_DELIVERIES: usize = 8;
+const MAX_ATTEMPTS: u32 = 8;
+const BASE_BACKOFF_SECS: i64 = 30;
+const MAX_BACKOFF_SECS: i64 = 6 * 3600;
+const DISPATCH_CURSOR: &str = "webhook_dispatch";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Deserialize)]
+struct Cursor {
+    seq: u64,
+}
+
+/// `sha256=<hex>` signature over `{timestamp}.{body}`; receivers recompute it
+/// with their secret and reject stale timestamps to prevent replays.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> Result<String, hmac::digest::InvalidLength> {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())?;
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    Ok(format!("sha256={}", hex::encode(mac.finalize().into_bytes())))
+}
+
+pub fn generate_secret() -> String {
+    format!("whsec_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Whether deliveries may go to `ip`, i.e. it is a public unicast address.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_public_v4(mapped),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        // Carrier-grade NAT, 100.64.0.0/10.
+        || (a == 100 && (b & 0xc0) == 64)
+        // Reserved, 240.0.0.0/4.
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, fc00::/7.
+        || (first & 0xfe00) == 0xfc00
+        // Link-local, fe80::/10.
+        || (first & 0xffc0) == 0xfe80
+        // Documentation, 2001:db8::/32.
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+/// The host of `url` when it is an IP address rather than a name.
+fn literal_ip(url: &reqwest::Url) -> Option<IpAddr> {
+    let host = url.host_str()?;
+    host.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+/// Checks that `url` only leads to public addresses. Names are resolved and
+/// every address they resolve to must be public.
+pub async fn check_destination(url: &reqwest::Url) -> Result<(), String> {
+    if let Some(ip) = literal_ip(url) {
+        if !is_public(ip) {
+            return Err(format!("{ip} is not a public address"));
+        }
+        return Ok(());
+    }
+
+    let host = url.host_str().ok_or("Url has no host")?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("Failed to resolve {host}: {e}"))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("{host} does not resolve to any address"));
+    }
+    match addrs.iter().find(|addr| !is_public(addr.ip())) {
+        Some(addr) => Err(format!("{host} resolves to {}, which is not a public address", addr.ip())),
+        None => Ok(()),
+    }
+}
+
+/// Resolver for the delivery client that drops non-public addresses, so the
+/// check holds for the address actually connected to.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                let error: Box<dyn StdError + Send + Sync> =
+                    format!("{host} does not resolve to a public address").into();
+                return Err(error);
+            }
+            let addrs: reqwest::dns::Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+fn matches(sub: &WebhookSubscription, event: &Event) -> bool {
+    if event.created_at < sub.created_at {
+        return false;
+    }
+    if !sub.event_types.is_empty() && !sub.event_types.contains(&event.kind) {
+        return false;
+    }
+    if !sub.conversation_ids.is_empty()
+        && !event.conversation_id.is_some_and(|id| sub.conversation_ids.contains(&id))
+    {
+        return false;
+    }
+    if !sub.channels.is_empty() {
+        let channel = event.data.as_document().and_then(|d| d.get_str("channel").ok());
+        return channel.is_some_and(|c| sub.channels.iter().any(|s| s.eq_ignore_ascii_case(c)));
+    }
+    true
+}
+
+fn backoff(attempts: u32) -> i64 {
+    let exp = attempts.saturating_sub(1).min(20);
+    (BASE_BACKOFF_SECS << exp).min(MAX_BACKOFF_SECS)
+}
+
+fn after(secs: i64) -> BsonDateTime {
+    BsonDateTime::from_millis(BsonDateTime::now().timestamp_millis() + secs * 1000)
+}
+
+/// Starts the dispatcher on the current runtime. It wakes up on every
+/// published event and otherwise polls for due retries.
+pub fn spawn_dispatcher(db: Database, events: EventBus) {
+    actix_web::rt::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicResolver))
+            .build();
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Webhook dispatcher disabled, failed to build HTTP client: {e}");
+                return;
+            }
+        };
+        let mut receiver = events.subscribe();
+
+        loop {
+            if let Err(e) = enqueue_deliveries(&db, &events).await {
+                eprintln!("Failed to enqueue webhook deliveries: {e}");
+            }
+            if let Err(e) = deliver_due(&db, &client).await {
+                eprintln!("Failed to deliver webhooks: {e}");
+            }
+
+            tokio::select! {
+                received = receiver.recv() => match received {
+                    Ok(_) | Err(RecvError::Lagged(_)) => {
+                        // One pass handles everything logged so far.
+                        while receiver.try_recv().is_ok() {}
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    });
+}
+
+/// Turns events logged since the dispatch cursor into deliveries. The
+/// cursor only moves after the deliveries are stored, so dispatchers on
+/// other instances, or this one after a crash, can go over the same events
+/// again; the unique index on subscription and event keeps the first
+/// delivery and rejects the repeats.
+async fn enqueue_deliveries(db: &Database, events: &EventBus) -> mongodb::error::Result<()> {
+    let cursor_coll = db.collection::<Cursor>("counters");
+    let sub_coll = db.collection::<WebhookSubscription>("webhooks");
+    let delivery_coll = db.collection::<WebhookDelivery>("webhook_deliveries");
+
+    // A fresh dispatcher starts at the end of the log instead of replaying history.
+    let head = events.head().await?;
+    let mut last_id = cursor_coll
+        .find_one_and_update(
+            doc! { "_id": DISPATCH_CURSOR },
+            doc! { "$setOnInsert": { "seq": head as i64 } },
+        )
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .await?
+        .map(|c| c.seq)
+        .unwrap_or(head);
+
+    loop {
+        let page = events.since(last_id, doc! {}, EVENT_BATCH).await?;
+        if page.next_since <= last_id {
+            return Ok(());
+        }
+        let batch = page.events;
+
+        let subs: Vec<WebhookSubscription> = sub_coll.find(doc! {}).await?.try_collect().await?;
+
+        let now = BsonDateTime::now();
+        let deliveries: Vec<WebhookDelivery> = batch.iter()
+            .flat_map(|event| {
+                subs.iter()
+                    .filter(|sub| matches(sub, event))
+                    .map(move |sub| WebhookDelivery {
+                        id: WebhookDeliveryId::generate(),
+                        subscription_id: sub.id,
+                        url: sub.url.clone(),
+                        event: event.clone(),
+                        status: WebhookDeliveryStatus::Pending,
+                        attempts: 0,
+                        next_attempt_at: Some(now),
+                        attempt_log: Vec::new(),
+                        created_at: now,
+                        updated_at: now,
+                    })
+            })
+            .collect();
+
+        if !deliveries.is_empty() {
+            // Unordered, so the deliveries not enqueued yet are still stored.
+            if let Err(e) = delivery_coll.insert_many(&deliveries).ordered(false).await {
+                if !only_duplicates(&e) {
+                    return Err(e);
+                }
+            }
+        }
+
+        cursor_coll
+            .update_one(
+                doc! { "_id": DISPATCH_CURSOR },
+                doc! { "$max": { "seq": page.next_since as i64 } },
+            )
+            .await?;
+        last_id = page.next_since;
+    }
+}
+
+/// Whether every document of a failed `insert_many` was already stored.
+fn only_duplicates(err: &mongodb::error::Error) -> bool {
+    match *err.kind {
+        ErrorKind::InsertMany(ref failure) => {
+            failure.write_concern_error.is_none()
+                && failure.write_errors.as_ref().is_some_and(|errors| errors.iter().all(|e| e.code == DUPLICATE_KEY))
+        }
+        _ => false,
+    }
+}
+
+async fn deliver_due(db: &Database, client: &reqwest::Client) -> mongodb::error::Result<()> {
+    let delivery_coll = db.collection::<WebhookDelivery>("webhook_deliveries");
+
+    let due: Vec<WebhookDelivery> = delivery_coll
+        .find(doc! { "status": "pending", "next_attempt_at": { "$lte": BsonDateTime::now() } })
+        .sort(doc! { "next_attempt_at": 1 })
+        .limit(DELIVERY_BATCH)
+        .await?
+        .try_collect()
+        .await?;
+
+    futures::stream::iter(due)
+        .for_each_concurrent(CONCURRENT_DELIVERIES, |delivery| async move {
+            if let Err(e) = attempt_delivery(db, client, delivery).await {
+                eprintln!("Failed to record webhook delivery attempt: {e}");
+            }
+        })
+        .await;
+
+    Ok(())
+}
+
+async fn attempt_delivery(
+    db: &Database,
+    client: &reqwest::Client,
+    delivery: WebhookDelivery,
+) -> mongodb::error::Result<()> {
+    let delivery_coll = db.collection::<WebhookDelivery>("webhook_deliveries");
+
+    // Pushing the due time past the request timeout claims the delivery, so
+    // another dispatcher skips it and a crash mid-request leads to a retry.
+    let claimed = delivery_coll
+        .update_one(
+            doc! {
+                "_id": &delivery.id,
+                "status": "pending",
+                "next_attempt_at": delivery.next_attempt_at
+            },
+            doc! { "$set": { "next_attempt_at": after(REQUEST_TIMEOUT.as_secs() as i64 * 2) } },
+        )
+        .await?;
+    if claimed.modified_count == 0 {
+        return Ok(());
+    }
+
+    let sub = db.collection::<WebhookSubscription>("webhooks")
+        .find_one(doc! { "_id": &delivery.subscription_id })
+        .await?;
+
+    let started = Instant::now();
+    let outcome = match &sub {
+        Some(sub) => send(client, sub, &delivery).await,
+        None => Err((None, "Subscription was deleted".to_string())),
+    };
+
+    let attempts = delivery.attempts + 1;
+    let (status, status_code, error) = match outcome {
+        Ok(code) => (WebhookDeliveryStatus::Delivered, Some(code), None),
+        Err((code, error)) if sub.is_none() || attempts >= MAX_ATTEMPTS => {
+            (WebhookDeliveryStatus::Dead, code, Some(error))
+        }
+        Err((code, error)) => (WebhookDeliveryStatus::Pending, code, Some(error)),
+    };
+    let next_attempt_at = match status {
+        WebhookDeliveryStatus::Pending => Some(after(backoff(attempts))),
+        _ => None,
+    };
+
+    let attempt = WebhookAttempt {
+        attempted_at: BsonDateTime::now(),
+        status_code,
+        error,
+        duration_ms: started.elapsed().as_millis() as u64,
+    };
+
+    delivery_coll
+        .update_one(
+            doc! { "_id": &delivery.id },
+            doc! {
+                "$set": {
+                    "status": bson::to_bson(&status)?,
+                    "attempts": attempts as i64,
+                    "next_attempt_at": next_attempt_at,
+                    "updated_at": BsonDateTime::now()
+                },
+                "$push": { "attempt_log": bson::to_bson(&attempt)? }
+            },
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Posts the signed event. Any 2xx response counts as delivered.
+async fn send(
+    client: &reqwest::Client,
+    sub: &WebhookSubscription,
+    delivery: &WebhookDelivery,
+) -> Result<u16, (Option<u16>, String)> {
+    // Names are checked by the resolver, addresses have to be checked here.
+    let url = reqwest::Url::parse(&delivery.url).map_err(|e| (None, e.to_string()))?;
+    if let Some(ip) = literal_ip(&url).filter(|ip| !is_public(*ip)) {
+        return Err((None, format!("{ip} is not a public address")));
+    }
+
+    let body = serde_json::to_vec(&delivery.event).map_err(|e| (None, e.to_string()))?;
+    let timestamp = chrono::Utc::now().timestamp();
+    let signature = sign(&sub.secret, timestamp, &body).map_err(|e| (None, e.to_string()))?;
+
+    let response = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Maratus-Event", delivery.event.kind.as_str())
+        .header("X-Maratus-Delivery", delivery.id.to_string())
+        .header("X-Maratus-Timestamp", timestamp.to_string())
+        .header("X-Maratus-Signature", signature)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| (None, e.to_string()))?;
+
+    let code = response.status().as_u16();
+    if response.status().is_success() {
+        Ok(code)
+    } else {
+        Err((Some(code), format!("Endpoint responded with {}", response.status())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_are_deliverable() {
+        let cases = [
+            ("93.184.216.34", true),
+            ("2606:2800:220:1:248:1893:25c8:1946", true),
+            ("127.0.0.1", false),
+            ("0.0.0.0", false),
+            ("10.1.2.3", false),
+            ("172.16.0.1", false),
+            ("172.32.0.1", true),
+            ("192.168.1.1", false),
+            ("169.254.169.254", false),
+            ("100.64.0.1", false),
+            ("100.128.0.1", true),
+            ("255.255.255.255", false),
+            ("::1", false),
+            ("::", false),
+            ("fd00::1", false),
+            ("fe80::1", false),
+            ("::ffff:127.0.0.1", false),
+            ("::ffff:93.184.216.34", true),
+        ];
+        for (ip, public) in cases {
+            assert_eq!(is_public(ip.parse().unwrap()), public, "{ip}");
+        }
+    }
+
+    #[test]
+    fn literal_hosts_are_recognized() {
+        let url = |s: &str| reqwest::Url::parse(s).unwrap();
+        assert_eq!(literal_ip(&url("http://127.0.0.1:8080/hook")), Some(IpAddr::from([127, 0, 0, 1])));
+        assert_eq!(literal_ip(&url("http://[::1]/hook")), Some(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert_eq!(literal_ip(&url("https://example.com/hook")), None);
+    }
+}